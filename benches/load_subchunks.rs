@@ -0,0 +1,65 @@
+// Measures how long it takes the world to load a subchunk, which is mostly
+// spent translating its palette into the block table. Run with
+// `cargo bench --bench load_subchunks`.
+
+use std::time::Instant;
+
+use mcworld::raw::{BlockProperties, BlockStorage, PaletteEntry, Subchunk, TargetVersion};
+use mcworld::{ChunkPos, Dimension, LocalPos, World};
+
+const NUM_CHUNKS: i32 = 2000;
+const PALETTE_LEN: u16 = 16;
+
+fn subchunk() -> Subchunk {
+    let palette = (0..PALETTE_LEN)
+        .map(|i| PaletteEntry {
+            name: format!("minecraft:block_{}", i),
+            properties: BlockProperties::Val(0),
+            extra_fields: Vec::new(),
+        })
+        .collect();
+    let blocks = (0..4096u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 28) as u16 % PALETTE_LEN)
+        .collect();
+
+    Subchunk {
+        version: 8,
+        block_storages: vec![BlockStorage { blocks, palette }],
+        opaque_storages: None,
+    }
+}
+
+fn main() {
+    let world = World::in_memory(TargetVersion::LATEST);
+    let sc = subchunk();
+    let chunks: Vec<ChunkPos> = (0..NUM_CHUNKS)
+        .map(|x| ChunkPos {
+            x,
+            z: 0,
+            dimension: Dimension::Overworld,
+        })
+        .collect();
+    for pos in &chunks {
+        world
+            .raw_world()
+            .save_subchunk(&pos.subchunk_pos(0), &sc)
+            .unwrap();
+    }
+
+    for round in 0..5 {
+        let start = Instant::now();
+        for pos in &chunks {
+            // only the bottom subchunk is loaded to read a block in it
+            let origin = LocalPos { x: 0, y: 0, z: 0 };
+            world.get_block(&pos.world_pos(origin)).unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        for pos in &chunks {
+            world.unload_chunk(*pos).unwrap();
+        }
+
+        let per_subchunk = elapsed.as_secs_f64() * 1e6 / f64::from(NUM_CHUNKS);
+        println!("round {}: {:.1} us per subchunk", round, per_subchunk);
+    }
+}
@@ -0,0 +1,130 @@
+use fnv::FnvHashMap;
+use std::collections::BTreeMap;
+use std::hash::Hash;
+
+// A map which keeps track of the order in which its entries were last used,
+// so the least recently used entry can be evicted.
+pub(crate) struct LruCache<K, V> {
+    entries: FnvHashMap<K, (V, u64)>,
+    // keys by the time they were last used
+    order: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K, V> LruCache<K, V>
+where
+    K: Copy + Hash + Eq,
+{
+    pub fn new() -> Self {
+        LruCache {
+            entries: FnvHashMap::default(),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    // returns the value and marks it as used
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let tick = self.tick();
+
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(tick, *key);
+        *last_used = tick;
+
+        Some(value)
+    }
+
+    // returns the value without marking it as used
+    pub fn peek_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.entries.get_mut(key).map(|(value, _)| value)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        let tick = self.tick();
+
+        if let Some((_, last_used)) = self.entries.insert(key, (value, tick)) {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(tick, key);
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, last_used) = self.entries.remove(key)?;
+        self.order.remove(&last_used);
+
+        Some(value)
+    }
+
+    // keys from the least recently used to the most recently used
+    pub fn keys_by_use(&self) -> impl Iterator<Item = &K> {
+        self.order.values()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, (value, _))| (key, value))
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(cache: &LruCache<u32, &str>) -> Vec<u32> {
+        cache.keys_by_use().cloned().collect()
+    }
+
+    #[test]
+    fn keys_are_ordered_by_use() {
+        let mut cache = LruCache::new();
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        cache.insert(3, "c");
+        assert_eq!(keys(&cache), vec![1, 2, 3]);
+
+        // getting a value marks it as used, peeking does not
+        assert_eq!(cache.get_mut(&1), Some(&mut "a"));
+        assert_eq!(cache.peek_mut(&2), Some(&mut "b"));
+        assert_eq!(cache.get(&2), Some(&"b"));
+        assert_eq!(keys(&cache), vec![2, 3, 1]);
+
+        // replacing a value marks it as used
+        cache.insert(2, "d");
+        assert_eq!(keys(&cache), vec![3, 1, 2]);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn removed_keys_leave_the_order() {
+        let mut cache = LruCache::new();
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+
+        assert_eq!(cache.remove(&1), Some("a"));
+        assert_eq!(cache.remove(&1), None);
+        assert_eq!(cache.get_mut(&1), None);
+        assert_eq!(keys(&cache), vec![2]);
+
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+        assert!(keys(&cache).is_empty());
+    }
+}
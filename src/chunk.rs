@@ -0,0 +1,197 @@
+use fnv::FnvHashMap;
+
+use crate::layer::Layer;
+use crate::pos::LocalPos;
+use crate::raw::{BlockStorage, OpaqueStorages};
+use crate::table::AIR;
+use crate::world::{BlockData, BlockLayers};
+
+pub(crate) const AIR_INFO: BlockData = BlockData {
+    block_id: AIR,
+    block_val: 0,
+};
+pub(crate) const NUM_SUBCHUNKS: u8 = 16;
+
+/// The blocks of a chunk, by their position within the chunk. A chunk
+/// handed out by the world always has all of its subchunks loaded, and can
+/// only be put back into the world it came from, since the block ids in it
+/// belong to that world.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    // id of the world whose block table the layers refer to
+    pub(crate) world_id: u64,
+    // this vector should always hold 16 subchunks, which are loaded when
+    // they are first used
+    pub(crate) subchunks: Vec<Option<WorldSubchunk>>,
+}
+
+/// A 16 block high part of a chunk. Positions in a subchunk have a `y` from
+/// 0 to 15.
+// uses indices into table stored in the World in the palettes of its layers
+#[derive(Debug, Clone)]
+pub struct WorldSubchunk {
+    pub(crate) data1: Layer,
+    pub(crate) data2: Layer,
+    pub(crate) preserved: PreservedData,
+    // whether the subchunk has changed since it was loaded or saved
+    pub(crate) dirty: bool,
+}
+
+// parts of a subchunk that the world does not understand, which are written
+// back unchanged when the subchunk is saved
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PreservedData {
+    // number of storages that were decoded
+    pub storages_read: usize,
+    // storages after the second one
+    pub extra_layers: Vec<BlockStorage>,
+    pub opaque_storages: Option<OpaqueStorages>,
+    // fields of palette entries that are not understood, by the block they
+    // belong to
+    pub extra_fields: FnvHashMap<BlockData, Vec<u8>>,
+}
+
+impl WorldSubchunk {
+    /// Whether the subchunk holds nothing but air, so it need not be stored.
+    pub fn is_empty(&self) -> bool {
+        self.data1.is_all(AIR_INFO)
+            && self.data2.is_all(AIR_INFO)
+            && self.preserved.extra_layers.is_empty()
+            && self.preserved.opaque_storages.is_none()
+    }
+
+    /// The blocks at the given position in the subchunk.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `x`, `y` or `z` is not below 16.
+    pub fn get(&self, x: u8, y: u8, z: u8) -> BlockLayers {
+        assert!(y < 16);
+        self.get_block(LocalPos { x, y, z }.subchunk_offset())
+    }
+
+    /// Sets the blocks at the given position in the subchunk.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `x`, `y` or `z` is not below 16.
+    pub fn set(&mut self, x: u8, y: u8, z: u8, data: BlockLayers) {
+        assert!(y < 16);
+        self.set_block(LocalPos { x, y, z }.subchunk_offset(), data)
+    }
+
+    /// Iterates over all blocks of the subchunk with their positions in it.
+    pub fn iter(&self) -> impl Iterator<Item = (LocalPos, BlockLayers)> + '_ {
+        self.blocks(0)
+    }
+
+    // the blocks with their positions, as if the subchunk was at the given
+    // height in the chunk
+    fn blocks(&self, subchunk_y: usize) -> impl Iterator<Item = (LocalPos, BlockLayers)> + '_ {
+        self.data1.iter().zip(self.data2.iter()).enumerate().map(
+            move |(offset, (layer1, layer2))| {
+                let pos = LocalPos::from_offset(subchunk_y, offset);
+                (pos, BlockLayers { layer1, layer2 })
+            },
+        )
+    }
+
+    pub(crate) fn get_block(&self, offset: usize) -> BlockLayers {
+        BlockLayers {
+            layer1: self.data1.get(offset),
+            layer2: self.data2.get(offset),
+        }
+    }
+
+    pub(crate) fn set_block(&mut self, offset: usize, d: BlockLayers) {
+        if self.data1.get(offset) != d.layer1 || self.data2.get(offset) != d.layer2 {
+            self.data1.set(offset, d.layer1);
+            self.data2.set(offset, d.layer2);
+            self.dirty = true;
+        }
+    }
+}
+
+impl Chunk {
+    /// The blocks at the given position in the chunk.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `x` or `z` is not below 16.
+    pub fn get(&self, x: u8, y: u8, z: u8) -> BlockLayers {
+        let pos = LocalPos { x, y, z };
+        self.subchunk(pos.subchunk_y())
+            .get_block(pos.subchunk_offset())
+    }
+
+    /// Sets the blocks at the given position in the chunk.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `x` or `z` is not below 16.
+    pub fn set(&mut self, x: u8, y: u8, z: u8, data: BlockLayers) {
+        let pos = LocalPos { x, y, z };
+        self.subchunk_mut(pos.subchunk_y())
+            .set_block(pos.subchunk_offset(), data)
+    }
+
+    /// Iterates over all blocks of the chunk with their positions in it,
+    /// from the bottom subchunk to the top one.
+    pub fn iter(&self) -> impl Iterator<Item = (LocalPos, BlockLayers)> + '_ {
+        self.subchunks()
+            .enumerate()
+            .flat_map(|(y, subchunk)| subchunk.blocks(y))
+    }
+
+    /// The subchunk at the given height, from 0 to 15.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `y` is not below 16, or if the subchunk is not loaded,
+    /// which cannot happen for chunks handed out by the world.
+    pub fn subchunk(&self, y: usize) -> &WorldSubchunk {
+        self.subchunks[y].as_ref().expect("subchunk is not loaded")
+    }
+
+    /// The subchunk at the given height, from 0 to 15.
+    ///
+    /// # Panics
+    ///
+    /// Panics like `subchunk`.
+    pub fn subchunk_mut(&mut self, y: usize) -> &mut WorldSubchunk {
+        self.subchunks[y].as_mut().expect("subchunk is not loaded")
+    }
+
+    /// The subchunks from the bottom one to the top one.
+    pub fn subchunks(&self) -> impl Iterator<Item = &WorldSubchunk> {
+        (0..self.subchunks.len()).map(move |y| self.subchunk(y))
+    }
+
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.subchunks.iter().flatten().any(|sc| sc.dirty)
+    }
+}
+
+pub(crate) fn create_air_layer() -> Layer {
+    Layer::Uniform(AIR_INFO)
+}
+
+pub(crate) fn create_air_subchunk() -> WorldSubchunk {
+    WorldSubchunk {
+        data1: create_air_layer(),
+        data2: create_air_layer(),
+        preserved: PreservedData::default(),
+        dirty: false,
+    }
+}
+
+// a new chunk of the given world, all of which has to be written
+pub(crate) fn create_air_chunk(world_id: u64) -> Chunk {
+    let mut sc = create_air_subchunk();
+    sc.dirty = true;
+    let subchunks = vec![Some(sc); usize::from(NUM_SUBCHUNKS)];
+    Chunk {
+        world_id,
+        subchunks,
+    }
+}
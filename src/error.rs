@@ -0,0 +1,246 @@
+use std::fmt;
+use std::io;
+
+use crate::raw::{SubchunkPos, ValidationReport};
+
+pub use failure::Error;
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// The ways in which stored world data can be malformed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    UnsupportedVersion(u8),
+    UnsupportedBitsPerBlock(u8),
+    BadStorageCount(usize),
+    BadPaletteIndex {
+        index: u16,
+        palette_len: usize,
+    },
+    /// The data ended before the record was complete.
+    Truncated,
+    UnknownDimension(u32),
+    /// The byte of a database key which tells the kind of record is not
+    /// the one expected.
+    BadRecordTag(u8),
+    /// The record was decoded, but bytes were left over.
+    TrailingBytes(usize),
+    /// A record of a fixed size has a different number of bytes.
+    BadLength {
+        expected: usize,
+        found: usize,
+    },
+    /// A field that must be present is missing, such as the name of a
+    /// palette entry.
+    MissingField(&'static str),
+    /// An NBT value has a different type than expected. Both are given as
+    /// NBT tags.
+    UnexpectedTag {
+        expected: u8,
+        found: u8,
+    },
+    /// An NBT tag that does not exist.
+    UnknownTag(u8),
+    /// A block state of a type other than byte, int or string.
+    BadStateType {
+        state: String,
+        tag: u8,
+    },
+    /// A numeric block ID of the oldest formats that is not known.
+    UnknownBlockId(u8),
+    /// A data value below zero.
+    NegativeDataValue(i16),
+    /// A runtime ID that is not in the runtime ID table.
+    UnknownRuntimeId(i32),
+    /// A storage with a palette of runtime IDs, read without a runtime ID
+    /// table.
+    MissingRuntimeIds,
+    /// A varint with more bytes than its type has room for.
+    VarintTooLong,
+    /// A negative length of a string, list or array.
+    NegativeLength(i32),
+    /// Lists and compounds nested deeper than the given number of levels.
+    NestedTooDeep(usize),
+    /// A string that is not valid UTF-8.
+    InvalidString,
+    /// Any other problem with the contents of a record, which does not fit
+    /// the kinds above.
+    Malformed(String),
+    /// The record was read, but its contents break the invariants of a
+    /// subchunk.
+    Invalid(ValidationReport),
+}
+
+/// An error for malformed world data, together with the location of the
+/// data in the world.
+#[derive(Debug, Clone)]
+pub struct DecodeError {
+    pub kind: DecodeErrorKind,
+    /// Offset in bytes from the start of the record at which the problem
+    /// was detected, if it was found while reading the record.
+    pub offset: Option<u64>,
+    pub pos: Option<SubchunkPos>,
+    /// The database key of the record, for records which do not belong to
+    /// a subchunk.
+    pub key: Option<Vec<u8>>,
+}
+
+impl DecodeError {
+    pub fn new(kind: DecodeErrorKind) -> DecodeError {
+        DecodeError {
+            kind,
+            offset: None,
+            pos: None,
+            key: None,
+        }
+    }
+
+    pub(crate) fn at_offset(kind: DecodeErrorKind, offset: u64) -> DecodeError {
+        DecodeError {
+            offset: Some(offset),
+            ..DecodeError::new(kind)
+        }
+    }
+
+    pub(crate) fn in_key(kind: DecodeErrorKind, key: &[u8]) -> DecodeError {
+        DecodeError {
+            key: Some(key.to_vec()),
+            ..DecodeError::new(kind)
+        }
+    }
+
+    // Turns any error that occurred while reading a record into a
+    // `DecodeError`, keeping the kind if it already was one.
+    pub(crate) fn from_error(e: Error, offset: u64) -> DecodeError {
+        match e.downcast::<DecodeError>() {
+            Ok(mut decode_error) => {
+                decode_error.offset = decode_error.offset.or(Some(offset));
+                decode_error
+            }
+            Err(e) => DecodeError::at_offset(error_kind(e), offset),
+        }
+    }
+
+    // Like `from_error`, for errors in a record that is not a subchunk.
+    pub(crate) fn from_record_error(e: Error, key: &[u8]) -> DecodeError {
+        match e.downcast::<DecodeError>() {
+            Ok(mut decode_error) => {
+                if decode_error.pos.is_none() {
+                    decode_error.key = Some(key.to_vec());
+                }
+                decode_error
+            }
+            Err(e) => DecodeError::in_key(error_kind(e), key),
+        }
+    }
+
+    // Records the subchunk in a `DecodeError`, other errors are passed on
+    // unchanged.
+    pub(crate) fn in_subchunk(e: Error, pos: SubchunkPos) -> Error {
+        match e.downcast::<DecodeError>() {
+            Ok(mut decode_error) => {
+                decode_error.pos = Some(pos);
+                decode_error.into()
+            }
+            Err(e) => e,
+        }
+    }
+}
+
+// the kind of an error from reading a record, such as an I/O or NBT error
+fn error_kind(e: Error) -> DecodeErrorKind {
+    let e = match e.downcast::<nbt::Error>() {
+        Ok(nbt::Error::IoError(io_error)) => io_error.into(),
+        Ok(nbt::Error::InvalidTypeId(tag)) => return DecodeErrorKind::UnknownTag(tag),
+        Ok(nbt::Error::InvalidUtf8) => return DecodeErrorKind::InvalidString,
+        Ok(nbt::Error::IncompleteNbtValue) => return DecodeErrorKind::Truncated,
+        Ok(nbt::Error::TagMismatch(expected, found)) => {
+            return DecodeErrorKind::UnexpectedTag { expected, found };
+        }
+        Ok(nbt_error) => return DecodeErrorKind::Malformed(nbt_error.to_string()),
+        Err(e) => e,
+    };
+
+    let e = match e.downcast::<std::string::FromUtf8Error>() {
+        Ok(_) => return DecodeErrorKind::InvalidString,
+        Err(e) => e,
+    };
+
+    match e.downcast::<io::Error>() {
+        Ok(ref io_error) if io_error.kind() == io::ErrorKind::UnexpectedEof => {
+            DecodeErrorKind::Truncated
+        }
+        Ok(io_error) => DecodeErrorKind::Malformed(io_error.to_string()),
+        Err(e) => DecodeErrorKind::Malformed(e.to_string()),
+    }
+}
+
+impl fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeErrorKind::UnsupportedVersion(v) => {
+                write!(f, "unsupported subchunk version {}", v)
+            }
+            DecodeErrorKind::UnsupportedBitsPerBlock(b) => {
+                write!(f, "unsupported number of bits per block {}", b)
+            }
+            DecodeErrorKind::BadStorageCount(c) => write!(f, "unexpected number of storages {}", c),
+            DecodeErrorKind::BadPaletteIndex { index, palette_len } => write!(
+                f,
+                "palette index {} out of range for palette of {} entries",
+                index, palette_len
+            ),
+            DecodeErrorKind::Truncated => write!(f, "data is truncated"),
+            DecodeErrorKind::UnknownDimension(d) => write!(f, "unknown dimension {}", d),
+            DecodeErrorKind::BadRecordTag(tag) => write!(f, "unexpected record tag {}", tag),
+            DecodeErrorKind::TrailingBytes(n) => write!(f, "{} trailing bytes", n),
+            DecodeErrorKind::BadLength { expected, found } => {
+                write!(f, "record has {} bytes instead of {}", found, expected)
+            }
+            DecodeErrorKind::MissingField(name) => write!(f, "missing field {}", name),
+            DecodeErrorKind::UnexpectedTag { expected, found } => {
+                write!(f, "expected tag {}, found tag {}", expected, found)
+            }
+            DecodeErrorKind::UnknownTag(tag) => write!(f, "unknown tag {}", tag),
+            DecodeErrorKind::BadStateType { state, tag } => {
+                write!(f, "unsupported tag {} for block state {}", tag, state)
+            }
+            DecodeErrorKind::UnknownBlockId(id) => write!(f, "unknown legacy block ID {}", id),
+            DecodeErrorKind::NegativeDataValue(val) => write!(f, "negative data value {}", val),
+            DecodeErrorKind::UnknownRuntimeId(id) => write!(f, "unknown runtime ID {}", id),
+            DecodeErrorKind::MissingRuntimeIds => {
+                write!(
+                    f,
+                    "storage uses runtime IDs, but no runtime ID table was given"
+                )
+            }
+            DecodeErrorKind::VarintTooLong => write!(f, "varint is too long"),
+            DecodeErrorKind::NegativeLength(len) => write!(f, "negative length {}", len),
+            DecodeErrorKind::NestedTooDeep(depth) => {
+                write!(f, "NBT is nested more than {} levels deep", depth)
+            }
+            DecodeErrorKind::InvalidString => write!(f, "string is not valid UTF-8"),
+            DecodeErrorKind::Malformed(msg) => write!(f, "{}", msg),
+            DecodeErrorKind::Invalid(report) => write!(f, "{}", report),
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+
+        if let Some(pos) = self.pos {
+            write!(f, " in subchunk {:?}", pos)?;
+        } else if let Some(ref key) = self.key {
+            write!(f, " in key {:02x?}", key)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for DecodeError {}
@@ -0,0 +1,140 @@
+use std::collections::VecDeque;
+
+use crate::pos::WorldPos;
+use crate::world::BlockLayers;
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct BlockChange {
+    pub pos: WorldPos,
+    pub old: BlockLayers,
+    pub new: BlockLayers,
+}
+
+#[derive(Debug)]
+pub(crate) struct Operation {
+    pub name: String,
+    // in the order they were made
+    pub changes: Vec<BlockChange>,
+    // an operation which grew beyond the limit of the history is not kept
+    complete: bool,
+}
+
+// The block changes made to a world, grouped into operations that can be
+// undone and redone. The oldest operations are forgotten once the history
+// holds more changes than its limit.
+#[derive(Debug)]
+pub(crate) struct History {
+    max_changes: usize,
+    done: VecDeque<Operation>,
+    // the most recently undone operation is last
+    undone: Vec<Operation>,
+    // the operation being recorded, and how many times it has been begun
+    // without being ended
+    current: Option<Operation>,
+    depth: usize,
+    // the number of changes held in all operations
+    len: usize,
+}
+
+impl History {
+    pub fn new(max_changes: usize) -> History {
+        History {
+            max_changes,
+            done: VecDeque::new(),
+            undone: Vec::new(),
+            current: None,
+            depth: 0,
+            len: 0,
+        }
+    }
+
+    // Starts recording an operation. An operation begun while another one
+    // is being recorded is part of that one.
+    pub fn begin(&mut self, name: &str) {
+        if self.depth == 0 {
+            self.current = Some(Operation {
+                name: name.to_owned(),
+                changes: Vec::new(),
+                complete: true,
+            });
+        }
+
+        self.depth += 1;
+    }
+
+    pub fn end(&mut self) {
+        self.depth -= 1;
+        if self.depth > 0 {
+            return;
+        }
+
+        let op = self.current.take().unwrap();
+        if op.complete && op.changes.is_empty() {
+            return;
+        }
+
+        // a new change makes the undone operations impossible to redo, so
+        // they are forgotten before any operation that can still be undone
+        for undone in self.undone.drain(..) {
+            self.len -= undone.changes.len();
+        }
+
+        if op.complete {
+            self.done.push_back(op);
+        }
+
+        while self.len > self.max_changes {
+            let forgotten = self.done.pop_front().unwrap();
+            self.len -= forgotten.changes.len();
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.depth > 0
+    }
+
+    pub fn record(&mut self, change: BlockChange) {
+        let op = self
+            .current
+            .as_mut()
+            .expect("no operation is being recorded");
+        if !op.complete {
+            return;
+        }
+
+        op.changes.push(change);
+        self.len += 1;
+
+        // older operations are only forgotten once the operation has ended,
+        // as they would not make room for one that is too large to keep
+        if op.changes.len() > self.max_changes {
+            self.len -= op.changes.len();
+            op.changes = Vec::new();
+            op.complete = false;
+        }
+    }
+
+    pub fn pop_done(&mut self) -> Option<Operation> {
+        let op = self.done.pop_back()?;
+        self.len -= op.changes.len();
+        Some(op)
+    }
+
+    pub fn pop_undone(&mut self) -> Option<Operation> {
+        let op = self.undone.pop()?;
+        self.len -= op.changes.len();
+        Some(op)
+    }
+
+    // operations that were undone or redone return to the history without
+    // affecting the operations that can be redone
+    pub fn push_done(&mut self, op: Operation) {
+        self.len += op.changes.len();
+        self.done.push_back(op);
+    }
+
+    pub fn push_undone(&mut self, op: Operation) {
+        self.len += op.changes.len();
+        self.undone.push(op);
+    }
+}
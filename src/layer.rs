@@ -0,0 +1,306 @@
+use fnv::FnvHashMap;
+
+use crate::world::BlockData;
+
+const LAYER_SIZE: usize = 4096;
+
+// The blocks of one layer of a subchunk. Most layers hold only a few kinds
+// of blocks, so they are stored as a palette with an index into it for each
+// block, and layers of a single block, such as air, as just that block.
+#[derive(Debug, Clone)]
+pub(crate) enum Layer {
+    Uniform(BlockData),
+    Paletted {
+        palette: Vec<BlockData>,
+        // the number of blocks using each palette entry
+        uses: Vec<u16>,
+        // the palette entry of each block, for finding it when it is set
+        lookup: FnvHashMap<BlockData, usize>,
+        // the number of palette entries that no block uses
+        unused: usize,
+        indices: Indices,
+    },
+}
+
+// indices take a byte per block until the palette grows beyond 256 entries
+#[derive(Debug, Clone)]
+pub(crate) enum Indices {
+    Narrow(Vec<u8>),
+    Wide(Vec<u16>),
+}
+
+impl Indices {
+    fn get(&self, i: usize) -> usize {
+        match self {
+            Indices::Narrow(indices) => usize::from(indices[i]),
+            Indices::Wide(indices) => usize::from(indices[i]),
+        }
+    }
+
+    fn set(&mut self, i: usize, index: usize) {
+        if let Indices::Narrow(indices) = self {
+            if index > usize::from(u8::MAX) {
+                *self = Indices::Wide(indices.iter().map(|i| u16::from(*i)).collect());
+            }
+        }
+
+        match self {
+            Indices::Narrow(indices) => indices[i] = index as u8,
+            Indices::Wide(indices) => indices[i] = index as u16,
+        }
+    }
+}
+
+impl Layer {
+    pub fn from_blocks(blocks: &[BlockData]) -> Layer {
+        debug_assert_eq!(blocks.len(), LAYER_SIZE);
+
+        let first = blocks[0];
+        if blocks.iter().all(|b| *b == first) {
+            return Layer::Uniform(first);
+        }
+
+        let mut mapping = FnvHashMap::default();
+        let mut palette = Vec::new();
+        let indices: Vec<u16> = blocks
+            .iter()
+            .map(|b| {
+                *mapping.entry(*b).or_insert_with(|| {
+                    palette.push(*b);
+                    palette.len() as u16 - 1
+                })
+            })
+            .collect();
+
+        Layer::paletted(palette, &indices)
+    }
+
+    // builds a layer from a palette and an index into it for each block,
+    // the indices must be in range
+    pub fn from_palette(palette: Vec<BlockData>, indices: &[u16]) -> Layer {
+        debug_assert_eq!(indices.len(), LAYER_SIZE);
+
+        let first = indices[0];
+        if indices.iter().all(|i| *i == first) {
+            return Layer::Uniform(palette[usize::from(first)]);
+        }
+
+        Layer::paletted(palette, indices)
+    }
+
+    fn paletted(palette: Vec<BlockData>, indices: &[u16]) -> Layer {
+        let mut uses = vec![0; palette.len()];
+        for index in indices {
+            uses[usize::from(*index)] += 1;
+        }
+        let unused = uses.iter().filter(|count| **count == 0).count();
+
+        // a block that is in the palette more than once is found at its
+        // first entry
+        let mut lookup = FnvHashMap::default();
+        for (index, block) in palette.iter().enumerate().rev() {
+            lookup.insert(*block, index);
+        }
+
+        let indices = if palette.len() <= 256 {
+            Indices::Narrow(indices.iter().map(|i| *i as u8).collect())
+        } else {
+            Indices::Wide(indices.to_vec())
+        };
+
+        Layer::Paletted {
+            palette,
+            uses,
+            lookup,
+            unused,
+            indices,
+        }
+    }
+
+    // Returns a palette holding every block in the layer once, in the order
+    // they first appear in but with `first` at the front if it is used,
+    // together with an index into it for each block.
+    pub fn to_palette(&self, first: BlockData) -> (Vec<BlockData>, Vec<u16>) {
+        let (palette, indices) = match self {
+            Layer::Uniform(block) => return (vec![*block], vec![0; LAYER_SIZE]),
+            Layer::Paletted {
+                palette, indices, ..
+            } => (palette, indices),
+        };
+
+        // the palette may hold unused entries and the same block more than
+        // once, so each entry is given its index in the new palette when it
+        // is first used
+        let mut mapping: Vec<Option<u16>> = vec![None; palette.len()];
+        let mut new_palette = Vec::new();
+        let mut new_indices: FnvHashMap<BlockData, u16> = FnvHashMap::default();
+
+        let used_first = (0..LAYER_SIZE).any(|i| palette[indices.get(i)] == first);
+        if used_first {
+            new_palette.push(first);
+            new_indices.insert(first, 0);
+        }
+
+        let blocks = (0..LAYER_SIZE)
+            .map(|i| {
+                let index = indices.get(i);
+                match mapping[index] {
+                    Some(new_index) => new_index,
+                    None => {
+                        let block = palette[index];
+                        let new_index = *new_indices.entry(block).or_insert_with(|| {
+                            new_palette.push(block);
+                            new_palette.len() as u16 - 1
+                        });
+                        mapping[index] = Some(new_index);
+                        new_index
+                    }
+                }
+            })
+            .collect();
+
+        (new_palette, blocks)
+    }
+
+    pub fn get(&self, i: usize) -> BlockData {
+        match self {
+            Layer::Uniform(block) => *block,
+            Layer::Paletted {
+                palette, indices, ..
+            } => palette[indices.get(i)],
+        }
+    }
+
+    pub fn set(&mut self, i: usize, block: BlockData) {
+        if let Layer::Uniform(uniform) = *self {
+            if uniform == block {
+                return;
+            }
+
+            *self = Layer::paletted(vec![uniform], &[0; LAYER_SIZE]);
+        }
+
+        if let Layer::Paletted {
+            palette,
+            uses,
+            lookup,
+            unused,
+            indices,
+        } = self
+        {
+            let old_index = indices.get(i);
+            if palette[old_index] == block {
+                return;
+            }
+
+            uses[old_index] -= 1;
+            if uses[old_index] == 0 {
+                *unused += 1;
+            }
+
+            let index = match lookup.get(&block) {
+                Some(index) => *index,
+                None => {
+                    palette.push(block);
+                    uses.push(0);
+                    lookup.insert(block, palette.len() - 1);
+                    *unused += 1;
+                    palette.len() - 1
+                }
+            };
+            if uses[index] == 0 {
+                *unused -= 1;
+            }
+            uses[index] += 1;
+            indices.set(i, index);
+
+            // blocks that are no longer used stay in the palette until they
+            // outnumber the ones that are, then the layer is rebuilt with
+            // the smallest indices that fit
+            if *unused > palette.len() - *unused {
+                let blocks: Vec<BlockData> = self.iter().collect();
+                *self = Layer::from_blocks(&blocks);
+            }
+        }
+    }
+
+    // whether every block in the layer is the given block
+    pub fn is_all(&self, block: BlockData) -> bool {
+        match self {
+            Layer::Uniform(uniform) => *uniform == block,
+            Layer::Paletted { .. } => self.iter().all(|b| b == block),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = BlockData> + '_ {
+        (0..LAYER_SIZE).map(move |i| self.get(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::AIR;
+
+    fn block(val: u16) -> BlockData {
+        BlockData {
+            block_id: AIR,
+            block_val: val,
+        }
+    }
+
+    fn palette_len(layer: &Layer) -> usize {
+        match layer {
+            Layer::Uniform(_) => 1,
+            Layer::Paletted { palette, .. } => palette.len(),
+        }
+    }
+
+    #[test]
+    fn unused_entries_are_dropped() {
+        let mut layer = Layer::Uniform(block(0));
+        layer.set(1, block(1));
+
+        for val in 2..1_000 {
+            // three blocks are in use, so at most as many are kept unused
+            layer.set(0, block(val));
+            assert!(palette_len(&layer) <= 7);
+        }
+
+        assert_eq!(layer.get(0), block(999));
+        assert_eq!(layer.get(1), block(1));
+        assert_eq!(layer.get(2), block(0));
+    }
+
+    #[test]
+    fn layer_narrows_after_compaction() {
+        let mut layer = Layer::Uniform(block(0));
+        for i in 0..300 {
+            layer.set(i, block(i as u16));
+        }
+        assert!(matches!(
+            layer,
+            Layer::Paletted {
+                indices: Indices::Wide(_),
+                ..
+            }
+        ));
+
+        for i in 0..300 {
+            layer.set(i, block(i as u16 % 2));
+        }
+        assert!(matches!(
+            layer,
+            Layer::Paletted {
+                indices: Indices::Narrow(_),
+                ..
+            }
+        ));
+
+        for i in 0..300 {
+            layer.set(i, block(0));
+        }
+        assert!(matches!(layer, Layer::Uniform(_)));
+        assert!(layer.is_all(block(0)));
+    }
+}
@@ -1,14 +1,24 @@
 #![warn(clippy::all)]
+mod cache;
+mod chunk;
+mod error;
+mod history;
+mod layer;
+mod observer;
 mod pos;
 pub mod raw;
+mod read_only;
+mod state;
 mod table;
+mod transaction;
 mod world;
 
-mod error {
-    pub use failure::Error;
-    pub type Result<T> = ::std::result::Result<T, Error>;
-}
-
+pub use crate::chunk::{Chunk, WorldSubchunk};
+pub use crate::error::{DecodeError, DecodeErrorKind};
 pub use crate::world::*;
+pub use crate::observer::WorldObserver;
 pub use crate::pos::*;
+pub use crate::read_only::*;
+pub use crate::state::*;
 pub use crate::table::BlockId;
+pub use crate::transaction::Transaction;
@@ -0,0 +1,20 @@
+use crate::pos::*;
+use crate::world::BlockLayers;
+
+/// Receives the changes made to a world, see `World::add_observer`. The
+/// methods are called on the thread that made the change, after the change
+/// was made. All methods do nothing by default.
+pub trait WorldObserver: Send + Sync {
+    /// A block was changed, by any means other than adding or replacing
+    /// its chunk. Undoing and redoing changes is reported as well.
+    fn block_changed(&self, _pos: &WorldPos, _old: BlockLayers, _new: BlockLayers) {}
+
+    /// A chunk was added or replaced as a whole, by `add_chunk` or
+    /// `put_chunk`.
+    fn chunk_added(&self, _pos: ChunkPos) {}
+
+    fn chunk_deleted(&self, _pos: ChunkPos) {}
+
+    /// All changes were written by `save`.
+    fn saved(&self) {}
+}
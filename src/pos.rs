@@ -15,7 +15,23 @@ pub struct WorldPos {
     pub dimension: Dimension,
 }
 
+/// A position within a chunk, `x` and `z` go from 0 to 15.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct LocalPos {
+    pub x: u8,
+    pub y: u8,
+    pub z: u8,
+}
+
 impl WorldPos {
+    pub fn local_pos(&self) -> LocalPos {
+        LocalPos {
+            x: (self.x - flooring_divide(self.x, 16) * 16) as u8,
+            y: self.y,
+            z: (self.z - flooring_divide(self.z, 16) * 16) as u8,
+        }
+    }
+
     pub fn chunk_pos(&self) -> ChunkPos {
         ChunkPos {
             x: flooring_divide(self.x, 16),
@@ -43,7 +59,43 @@ impl WorldPos {
     }
 }
 
+impl LocalPos {
+    pub fn subchunk_y(&self) -> usize {
+        usize::from(self.y / 16)
+    }
+
+    pub fn subchunk_offset(&self) -> usize {
+        assert!(self.x < 16 && self.z < 16);
+
+        let (x, y, z) = (
+            usize::from(self.x),
+            usize::from(self.y),
+            usize::from(self.z),
+        );
+        16 * 16 * x + 16 * z + y % 16
+    }
+
+    // the position of a block in the given subchunk, the inverse of
+    // subchunk_y and subchunk_offset
+    pub(crate) fn from_offset(subchunk_y: usize, offset: usize) -> LocalPos {
+        LocalPos {
+            x: (offset / (16 * 16)) as u8,
+            y: (subchunk_y * 16 + offset % 16) as u8,
+            z: (offset / 16 % 16) as u8,
+        }
+    }
+}
+
 impl ChunkPos {
+    pub fn world_pos(&self, local: LocalPos) -> WorldPos {
+        WorldPos {
+            x: self.x * 16 + i32::from(local.x),
+            y: local.y,
+            z: self.z * 16 + i32::from(local.z),
+            dimension: self.dimension,
+        }
+    }
+
     pub fn subchunk_pos(&self, subchunk: u8) -> SubchunkPos {
         SubchunkPos {
             x: self.x,
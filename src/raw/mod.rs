@@ -1,8 +1,18 @@
 mod encode;
+mod scan;
+mod storage;
+mod stream;
 mod subchunk;
+mod upgrade;
+mod version;
 mod world;
 mod pos;
 
+pub use scan::*;
+pub use storage::*;
+pub use stream::*;
 pub use subchunk::*;
+pub use upgrade::*;
+pub use version::*;
 pub use world::*;
 pub use pos::*;
@@ -1,12 +1,18 @@
 use byteorder::{LittleEndian, WriteBytesExt};
 use std::io::Write;
 use crate::raw::encode::Encode;
-use crate::Dimension;
+use crate::{ChunkPos, Dimension};
 use crate::error::*;
 
 pub(crate) const SUBCHUNK_KEY_LEN_OVERWORLD: usize = 10;
 pub(crate) const SUBCHUNK_KEY_LEN_OTHER: usize = 14;
 pub(crate) const SUBCHUNK_PREFIX: u8 = 47;
+pub(crate) const VERSION_PREFIX: u8 = 44;
+pub(crate) const VERSION_OLD_PREFIX: u8 = 118;
+pub(crate) const DATA_3D_PREFIX: u8 = 43;
+pub(crate) const DATA_2D_PREFIX: u8 = 45;
+pub(crate) const BLOCK_ENTITY_PREFIX: u8 = 49;
+pub(crate) const ENTITY_PREFIX: u8 = 50;
 
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct SubchunkPos {
@@ -20,13 +26,36 @@ impl Encode for SubchunkPos {
     type Error = Error;
 
     fn encode<T: Write>(&self, buf: &mut T) -> Result<()> {
-        buf.write_i32::<LittleEndian>(self.x)?;
-        buf.write_i32::<LittleEndian>(self.z)?;
-        if self.dimension != Dimension::Overworld {
-            buf.write_u32::<LittleEndian>(self.dimension as u32)?;
-        }
+        encode_chunk_prefix(buf, self.x, self.z, self.dimension)?;
         buf.write_all(&[SUBCHUNK_PREFIX])?;
         buf.write_all(&[self.subchunk])?;
         Ok(())
     }
 }
+
+// key of a record that is stored once for the whole chunk, such as the chunk
+// version
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub(crate) struct ChunkKey {
+    pub pos: ChunkPos,
+    pub tag: u8,
+}
+
+impl Encode for ChunkKey {
+    type Error = Error;
+
+    fn encode<T: Write>(&self, buf: &mut T) -> Result<()> {
+        encode_chunk_prefix(buf, self.pos.x, self.pos.z, self.pos.dimension)?;
+        buf.write_all(&[self.tag])?;
+        Ok(())
+    }
+}
+
+fn encode_chunk_prefix<T: Write>(buf: &mut T, x: i32, z: i32, dimension: Dimension) -> Result<()> {
+    buf.write_i32::<LittleEndian>(x)?;
+    buf.write_i32::<LittleEndian>(z)?;
+    if dimension != Dimension::Overworld {
+        buf.write_u32::<LittleEndian>(dimension as u32)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,478 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::BTreeSet;
+use std::io::{self, Cursor, Read};
+
+use crate::error::*;
+use crate::pos::*;
+use crate::raw::pos::{
+    BLOCK_ENTITY_PREFIX, DATA_2D_PREFIX, DATA_3D_PREFIX, ENTITY_PREFIX, SUBCHUNK_PREFIX,
+    VERSION_OLD_PREFIX, VERSION_PREFIX,
+};
+use crate::raw::subchunk::{
+    read_compound, BlockProperties, BlockStorage, PaletteEntry, Subchunk, BITS_PER_BLOCK_OPTIONS,
+};
+use crate::chunk::NUM_SUBCHUNKS;
+use crate::raw::upgrade::LATEST_CHUNK_VERSION;
+use crate::raw::version::TargetVersion;
+use crate::raw::world::{decode_subchunk, RawWorld};
+
+// a heightmap of 256 shorts followed by 256 biome IDs
+const DATA_2D_LEN: usize = 768;
+const HEIGHTMAP_LEN: u64 = 512;
+
+// header of a 3D biome storage that repeats the storage below it
+const BIOMES_REPEAT: u8 = 0xff;
+
+/// The kinds of records stored for a chunk that a scan checks.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum RecordKind {
+    /// The blocks of the subchunk at the given height.
+    Subchunk(u8),
+    ChunkVersion,
+    BlockEntities,
+    Entities,
+    /// The heightmap and 2D biomes, as used before Minecraft 1.18.
+    Data2D,
+    /// The heightmap and 3D biomes, as used by Minecraft 1.18 and later.
+    Data3D,
+}
+
+#[derive(Debug)]
+pub enum RecordProblem {
+    /// The record could not be decoded.
+    Corrupt(Error),
+    /// The record belongs to a chunk which has neither subchunks nor a
+    /// chunk version, so the game never loads it.
+    Dangling,
+}
+
+/// What a salvage did with a broken record.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SalvageAction {
+    ReplacedWithAir,
+    /// A chunk version was written that matches the format of the
+    /// subchunks of the chunk.
+    ReplacedVersion,
+    RestoredFromBackup,
+    Removed,
+}
+
+#[derive(Debug)]
+pub struct ScanIssue {
+    pub key: Vec<u8>,
+    pub pos: ChunkPos,
+    pub kind: RecordKind,
+    pub problem: RecordProblem,
+    /// The repair made by a salvage, `None` if the record was left alone.
+    pub action: Option<SalvageAction>,
+}
+
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    /// Number of chunk records that were checked.
+    pub records: usize,
+    /// Number of chunks with subchunks or a chunk version.
+    pub chunks: usize,
+    pub issues: Vec<ScanIssue>,
+}
+
+impl ScanReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct SalvageOptions<'a> {
+    /// A copy of the world to restore broken records from. A record is
+    /// restored only if the copy of it can be decoded.
+    pub backup: Option<&'a RawWorld>,
+    /// Also remove dangling records.
+    pub remove_dangling: bool,
+}
+
+impl RawWorld {
+    /// Attempts to decode every chunk record in the database and reports
+    /// the ones which are broken. Records that are not part of a chunk,
+    /// such as players and villages, are not checked.
+    pub fn scan(&self) -> Result<ScanReport> {
+        let mut report = ScanReport::default();
+
+        // chunks which the game will load, and records which are only valid
+        // as part of such a chunk
+        let mut chunks = BTreeSet::new();
+        let mut chunk_records = Vec::new();
+
+        let mut iter = self.iter_records();
+        while iter.valid() {
+            let key = iter.key();
+
+            if let Some((pos, kind)) = parse_record_key(key) {
+                report.records += 1;
+
+                // the chunk is known from the key alone, so a broken subchunk
+                // or chunk version does not make the other records of the
+                // chunk dangling
+                let marks_chunk =
+                    matches!(kind, RecordKind::Subchunk(_) | RecordKind::ChunkVersion);
+                if marks_chunk {
+                    chunks.insert(pos);
+                }
+
+                match decode_record(pos, kind, iter.value()) {
+                    Ok(()) if !marks_chunk => chunk_records.push((key.to_vec(), pos, kind)),
+                    Ok(()) => {}
+                    Err(e) => report.issues.push(ScanIssue {
+                        key: key.to_vec(),
+                        pos,
+                        kind,
+                        problem: RecordProblem::Corrupt(
+                            DecodeError::from_record_error(e, key).into(),
+                        ),
+                        action: None,
+                    }),
+                }
+            }
+
+            iter.next();
+        }
+
+        for (key, pos, kind) in chunk_records {
+            if !chunks.contains(&pos) {
+                report.issues.push(ScanIssue {
+                    key,
+                    pos,
+                    kind,
+                    problem: RecordProblem::Dangling,
+                    action: None,
+                });
+            }
+        }
+
+        report.chunks = chunks.len();
+
+        Ok(report)
+    }
+
+    /// Scans the world and repairs the broken records. A broken record is
+    /// restored from the backup if possible, otherwise broken subchunks are
+    /// replaced with air, broken chunk versions are replaced with one that
+    /// fits the subchunks and other broken records are removed.
+    pub fn salvage(&self, options: &SalvageOptions) -> Result<ScanReport> {
+        // the scan finishes before anything is written, so the database is
+        // not modified while it is being iterated
+        let mut report = self.scan()?;
+
+        for issue in &mut report.issues {
+            issue.action = self.salvage_record(issue, options)?;
+        }
+
+        Ok(report)
+    }
+
+    // The chunk version matching the newest format among the subchunks of
+    // a chunk which can be read, or the latest chunk version if there are
+    // none.
+    fn subchunks_chunk_version(&self, pos: &ChunkPos) -> Result<u8> {
+        let mut target = None;
+        for y in 0..NUM_SUBCHUNKS {
+            if let Ok(Some(sc)) = self.load_subchunk(&pos.subchunk_pos(y)) {
+                target = target.max(Some(TargetVersion::of_subchunk(&sc)));
+            }
+        }
+
+        Ok(target
+            .map(TargetVersion::chunk_version)
+            .unwrap_or(LATEST_CHUNK_VERSION))
+    }
+
+    fn salvage_record(
+        &self,
+        issue: &ScanIssue,
+        options: &SalvageOptions,
+    ) -> Result<Option<SalvageAction>> {
+        if let RecordProblem::Dangling = issue.problem {
+            if !options.remove_dangling {
+                return Ok(None);
+            }
+
+            self.delete_bytes(&issue.key)?;
+            return Ok(Some(SalvageAction::Removed));
+        }
+
+        if let Some(backup) = options.backup {
+            if let Some(data) = backup.get_bytes(&issue.key)? {
+                if decode_record(issue.pos, issue.kind, &data).is_ok() {
+                    self.put_bytes(&issue.key, &data)?;
+                    return Ok(Some(SalvageAction::RestoredFromBackup));
+                }
+            }
+        }
+
+        match issue.kind {
+            RecordKind::Subchunk(y) => {
+                // write air in the format of the chunk, so the chunk stays
+                // readable by the game version that wrote it
+                let target = match self.load_chunk_version(&issue.pos)? {
+                    Some(version) => TargetVersion::from_chunk_version(version),
+                    None => TargetVersion::LATEST,
+                };
+
+                self.save_subchunk(&issue.pos.subchunk_pos(y), &air_subchunk(target))?;
+                Ok(Some(SalvageAction::ReplacedWithAir))
+            }
+            RecordKind::ChunkVersion => {
+                // without a chunk version the game generates the chunk anew,
+                // so a version is written rather than removing the record
+                let version = self.subchunks_chunk_version(&issue.pos)?;
+                self.put_bytes(&issue.key, &[version])?;
+                Ok(Some(SalvageAction::ReplacedVersion))
+            }
+            _ => {
+                self.delete_bytes(&issue.key)?;
+                Ok(Some(SalvageAction::Removed))
+            }
+        }
+    }
+}
+
+// Returns the chunk and kind of a chunk record, or `None` for keys of other
+// records.
+pub(crate) fn parse_record_key(key: &[u8]) -> Option<(ChunkPos, RecordKind)> {
+    // chunk records are keyed by the chunk position and a tag, subchunks
+    // have their height after the tag
+    let (prefix_len, subchunk) = match key.len() {
+        9 | 13 => (key.len() - 1, None),
+        10 | 14 if key[key.len() - 2] == SUBCHUNK_PREFIX => {
+            (key.len() - 2, Some(key[key.len() - 1]))
+        }
+        _ => return None,
+    };
+
+    let kind = match (key[prefix_len], subchunk) {
+        (SUBCHUNK_PREFIX, Some(y)) => RecordKind::Subchunk(y),
+        (VERSION_PREFIX, None) | (VERSION_OLD_PREFIX, None) => RecordKind::ChunkVersion,
+        (BLOCK_ENTITY_PREFIX, None) => RecordKind::BlockEntities,
+        (ENTITY_PREFIX, None) => RecordKind::Entities,
+        (DATA_2D_PREFIX, None) => RecordKind::Data2D,
+        (DATA_3D_PREFIX, None) => RecordKind::Data3D,
+        _ => return None,
+    };
+
+    let mut cursor = Cursor::new(&key[..prefix_len]);
+    let x = cursor.read_i32::<LittleEndian>().ok()?;
+    let z = cursor.read_i32::<LittleEndian>().ok()?;
+    let dimension = if prefix_len == 8 {
+        Dimension::Overworld
+    } else {
+        // other keys of this length are not chunk records
+        match cursor.read_u32::<LittleEndian>().ok()? {
+            1 => Dimension::Nether,
+            2 => Dimension::End,
+            _ => return None,
+        }
+    };
+
+    Some((ChunkPos { x, z, dimension }, kind))
+}
+
+fn decode_record(pos: ChunkPos, kind: RecordKind, data: &[u8]) -> Result<()> {
+    match kind {
+        RecordKind::Subchunk(y) => {
+            // subchunks written by newer versions of the game are not broken
+            // just because they are not fully understood
+            decode_subchunk(&pos.subchunk_pos(y), data, true)?;
+        }
+        RecordKind::ChunkVersion => {
+            if data.len() != 1 {
+                let kind = DecodeErrorKind::BadLength {
+                    expected: 1,
+                    found: data.len(),
+                };
+                return Err(DecodeError::new(kind).into());
+            }
+        }
+        RecordKind::BlockEntities | RecordKind::Entities => decode_compounds(data)?,
+        RecordKind::Data2D => {
+            if data.len() != DATA_2D_LEN {
+                let kind = DecodeErrorKind::BadLength {
+                    expected: DATA_2D_LEN,
+                    found: data.len(),
+                };
+                return Err(DecodeError::new(kind).into());
+            }
+        }
+        RecordKind::Data3D => decode_data_3d(data)?,
+    }
+
+    Ok(())
+}
+
+// entities and block entities are stored as a sequence of compound tags
+fn decode_compounds(data: &[u8]) -> Result<()> {
+    let mut cursor = Cursor::new(data);
+    while (cursor.position() as usize) < data.len() {
+        read_compound(&mut cursor)?;
+    }
+
+    Ok(())
+}
+
+fn decode_data_3d(data: &[u8]) -> Result<()> {
+    let mut cursor = Cursor::new(data);
+    skip(&mut cursor, HEIGHTMAP_LEN)?;
+
+    // the heightmap is followed by a biome storage for each subchunk, which
+    // is laid out like a block storage with biome IDs in the palette
+    while (cursor.position() as usize) < data.len() {
+        let header = cursor.read_u8()?;
+        if header == BIOMES_REPEAT {
+            continue;
+        }
+
+        let bits_per_block = header >> 1;
+        if !BITS_PER_BLOCK_OPTIONS.contains(&bits_per_block) {
+            let kind = DecodeErrorKind::UnsupportedBitsPerBlock(bits_per_block);
+            return Err(DecodeError::at_offset(kind, cursor.position() - 1).into());
+        }
+
+        let num_entries = if bits_per_block == 0 {
+            1
+        } else {
+            let blocks_per_word = 32 / u64::from(bits_per_block);
+            // rounded up by hand, as `u64::div_ceil` needs Rust 1.73
+            #[allow(clippy::manual_div_ceil)]
+            let num_words = (4096 + blocks_per_word - 1) / blocks_per_word;
+            skip(&mut cursor, num_words * 4)?;
+
+            u64::from(cursor.read_u32::<LittleEndian>()?)
+        };
+        skip(&mut cursor, num_entries * 4)?;
+    }
+
+    Ok(())
+}
+
+fn skip<R: Read>(reader: &mut R, len: u64) -> Result<()> {
+    let skipped = io::copy(&mut reader.take(len), &mut io::sink())?;
+    if skipped != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    Ok(())
+}
+
+pub(crate) fn air_subchunk(target: TargetVersion) -> Subchunk {
+    let properties = if target.block_states() {
+        BlockProperties::States {
+            states: Default::default(),
+            version: target.block_version(),
+        }
+    } else {
+        BlockProperties::Val(0)
+    };
+
+    let storage = BlockStorage {
+        blocks: vec![0; 4096],
+        palette: vec![PaletteEntry {
+            name: "minecraft:air".to_owned(),
+            properties,
+            extra_fields: Vec::new(),
+        }],
+    };
+
+    Subchunk {
+        version: target.subchunk_version(),
+        block_storages: vec![storage],
+        opaque_storages: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_key(tag: u8) -> Vec<u8> {
+        let mut key = vec![0; 8];
+        key.push(tag);
+        key
+    }
+
+    #[test]
+    fn broken_chunk_version_keeps_chunk() {
+        let world = RawWorld::in_memory();
+        // a chunk version is a single byte
+        world
+            .put_bytes(&chunk_key(VERSION_PREFIX), &[1, 2])
+            .unwrap();
+        world.put_bytes(&chunk_key(ENTITY_PREFIX), &[]).unwrap();
+
+        let report = world.scan().unwrap();
+        assert_eq!(report.chunks, 1);
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!(report.issues[0].kind, RecordKind::ChunkVersion);
+        assert!(matches!(
+            report.issues[0].problem,
+            RecordProblem::Corrupt(_)
+        ));
+
+        let options = SalvageOptions {
+            backup: None,
+            remove_dangling: true,
+        };
+        let report = world.salvage(&options).unwrap();
+        assert_eq!(
+            report.issues[0].action,
+            Some(SalvageAction::ReplacedVersion)
+        );
+        assert!(world
+            .get_bytes(&chunk_key(ENTITY_PREFIX))
+            .unwrap()
+            .is_some());
+
+        // there are no subchunks to take the version from
+        let version = world.get_bytes(&chunk_key(VERSION_PREFIX)).unwrap();
+        assert_eq!(version, Some(vec![LATEST_CHUNK_VERSION]));
+    }
+
+    #[test]
+    fn broken_chunk_version_follows_subchunks() {
+        let pos = ChunkPos {
+            x: 0,
+            z: 0,
+            dimension: Dimension::Overworld,
+        };
+
+        for &target in &[
+            TargetVersion::SingleStorage,
+            TargetVersion::DataValues,
+            TargetVersion::EarlyBlockStates,
+            TargetVersion::BlockStates,
+        ] {
+            let world = RawWorld::in_memory();
+            world
+                .save_subchunk(&pos.subchunk_pos(0), &air_subchunk(target))
+                .unwrap();
+            world.put_bytes(&chunk_key(VERSION_PREFIX), &[]).unwrap();
+
+            let options = SalvageOptions {
+                backup: None,
+                remove_dangling: true,
+            };
+            world.salvage(&options).unwrap();
+
+            let version = world.load_chunk_version(&pos).unwrap().unwrap();
+            assert_eq!(TargetVersion::from_chunk_version(version), target);
+        }
+    }
+
+    #[test]
+    fn records_without_chunk_are_dangling() {
+        let world = RawWorld::in_memory();
+        world.put_bytes(&chunk_key(ENTITY_PREFIX), &[]).unwrap();
+
+        let report = world.scan().unwrap();
+        assert_eq!(report.chunks, 0);
+        assert!(matches!(report.issues[0].problem, RecordProblem::Dangling));
+    }
+}
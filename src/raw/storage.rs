@@ -0,0 +1,476 @@
+use failure::bail;
+use leveldb::batch::Writebatch;
+use leveldb::database::iterator::DatabaseIterator;
+use leveldb::database::Database;
+use leveldb::options::{Compression, Options, ReadOptions, WriteOptions};
+use leveldb::snapshots::Snapshot;
+use ouroboros::self_referencing;
+use parking_lot::RwLock;
+use std::collections::{btree_map, BTreeMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::error::*;
+
+/// A store of records by key, which holds the data of a world. Records are
+/// kept in the order of their keys.
+pub trait Storage: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    fn delete(&self, key: &[u8]) -> Result<()>;
+
+    /// Applies all operations at once. With `sync`, the call only returns
+    /// once the changes have reached the disk, for storages that have one.
+    fn write(&self, ops: &[BatchOp], sync: bool) -> Result<()>;
+
+    /// Iterates over the records in the order of their keys. The iterator
+    /// starts out before the first record, and does not see the changes
+    /// made after it was created.
+    fn iter(&self) -> Box<dyn StorageIterator + '_>;
+
+    /// Whether every write fails.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+/// A cursor over the records of a storage, in the style of the LevelDB
+/// iterator.
+pub trait StorageIterator {
+    fn seek_to_first(&mut self);
+
+    /// Whether the cursor is at a record.
+    fn valid(&self) -> bool;
+
+    fn key(&self) -> &[u8];
+
+    fn value(&self) -> &[u8];
+
+    fn next(&mut self);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+/// The LevelDB database a world is normally stored in.
+pub struct LevelDbStorage {
+    database: Database,
+}
+
+impl LevelDbStorage {
+    pub fn open(path: &Path) -> Result<LevelDbStorage> {
+        let database = open_database(path)?;
+        Ok(LevelDbStorage { database })
+    }
+}
+
+impl Storage for LevelDbStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let read_options = ReadOptions::default();
+        Ok(self.database.get_bytes(&read_options, key)?)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let write_options = WriteOptions::default();
+        self.database.put(&write_options, key, value)?;
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let write_options = WriteOptions::default();
+        self.database.delete(&write_options, key)?;
+
+        Ok(())
+    }
+
+    fn write(&self, ops: &[BatchOp], sync: bool) -> Result<()> {
+        let mut batch = Writebatch::new();
+        for op in ops {
+            match op {
+                BatchOp::Put { key, value } => batch.put(key, value),
+                BatchOp::Delete { key } => batch.delete(key),
+            }
+        }
+
+        let mut write_options = WriteOptions::default();
+        write_options.sync = sync;
+        self.database.write(&write_options, &batch)?;
+
+        Ok(())
+    }
+
+    fn iter(&self) -> Box<dyn StorageIterator + '_> {
+        let read_options = ReadOptions::default();
+        Box::new(self.database.iter(&read_options))
+    }
+}
+
+/// A LevelDB database as it was when it was opened. Writes made to the
+/// database later on through other handles are not seen, and writing to
+/// the snapshot fails.
+///
+/// LevelDB has no read-only mode, so `open` opens the database like any
+/// other: it takes the lock of the database, which fails while another
+/// process such as the game has it open, and it may replay the log and
+/// write a new manifest. Use `open_copy` to leave the database untouched.
+pub struct LevelDbSnapshot {
+    inner: SnapshotDatabase,
+    // the copy the database was opened from, which is removed once the
+    // database is closed. fields are dropped in order, so this comes last
+    _copy: Option<TempCopy>,
+}
+
+#[self_referencing]
+struct SnapshotDatabase {
+    database: Database,
+    #[borrows(database)]
+    #[covariant]
+    snapshot: Snapshot<'this>,
+}
+
+impl LevelDbSnapshot {
+    pub fn open(path: &Path) -> Result<LevelDbSnapshot> {
+        let database = open_database(path)?;
+        let inner = SnapshotDatabase::new(database, |database| database.snapshot());
+
+        Ok(LevelDbSnapshot { inner, _copy: None })
+    }
+
+    /// Copies the files of the database to a temporary directory and opens
+    /// the copy, which is removed again when the snapshot is dropped. The
+    /// database itself is only read, so this works while another process
+    /// has it open. If that process writes to the database while it is
+    /// being copied, the copy may be inconsistent and fail to open, in
+    /// which case trying again usually helps.
+    pub fn open_copy(path: &Path) -> Result<LevelDbSnapshot> {
+        let copy = TempCopy::of_database(path)?;
+        let database = open_database(&copy.path)?;
+        let inner = SnapshotDatabase::new(database, |database| database.snapshot());
+
+        Ok(LevelDbSnapshot {
+            inner,
+            _copy: Some(copy),
+        })
+    }
+}
+
+// number of copies made by this process so far, to give each a new name
+static NUM_COPIES: AtomicUsize = AtomicUsize::new(0);
+
+// a copy of a database in the temporary directory, removed when dropped
+struct TempCopy {
+    path: PathBuf,
+}
+
+impl TempCopy {
+    fn of_database(path: &Path) -> Result<TempCopy> {
+        let name = format!(
+            "mcworld-{}-{}",
+            std::process::id(),
+            NUM_COPIES.fetch_add(1, Ordering::Relaxed)
+        );
+        let copy = TempCopy {
+            path: std::env::temp_dir().join(name),
+        };
+        fs::create_dir(&copy.path)?;
+
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            // the lock belongs to whoever has the database open
+            if entry.file_name() == "LOCK" || !entry.file_type()?.is_file() {
+                continue;
+            }
+            fs::copy(entry.path(), copy.path.join(entry.file_name()))?;
+        }
+
+        Ok(copy)
+    }
+}
+
+impl Drop for TempCopy {
+    fn drop(&mut self) {
+        // nothing can be done about a copy that cannot be removed
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// The snapshot is a raw LevelDB handle, so the compiler does not know that
+// it can be shared. LevelDB allows a database to be used from several
+// threads at once, and a snapshot never changes once it is taken, it is
+// only passed along with reads. The snapshot borrows the database, which
+// is kept on the heap by `SnapshotDatabase` and so stays in place when the
+// storage moves to another thread.
+unsafe impl Send for LevelDbSnapshot {}
+unsafe impl Sync for LevelDbSnapshot {}
+
+impl Storage for LevelDbSnapshot {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let read_options = ReadOptions::default();
+        Ok(self.inner.borrow_snapshot().get_bytes(&read_options, key)?)
+    }
+
+    fn put(&self, _key: &[u8], _value: &[u8]) -> Result<()> {
+        bail!("the world is opened read-only")
+    }
+
+    fn delete(&self, _key: &[u8]) -> Result<()> {
+        bail!("the world is opened read-only")
+    }
+
+    fn write(&self, _ops: &[BatchOp], _sync: bool) -> Result<()> {
+        bail!("the world is opened read-only")
+    }
+
+    fn iter(&self) -> Box<dyn StorageIterator + '_> {
+        let read_options = ReadOptions::default();
+        Box::new(self.inner.borrow_snapshot().iter(&read_options))
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+impl<'a> StorageIterator for DatabaseIterator<'a> {
+    fn seek_to_first(&mut self) {
+        DatabaseIterator::seek_to_first(self)
+    }
+
+    fn valid(&self) -> bool {
+        DatabaseIterator::valid(self)
+    }
+
+    fn key(&self) -> &[u8] {
+        DatabaseIterator::key(self)
+    }
+
+    fn value(&self) -> &[u8] {
+        DatabaseIterator::value(self)
+    }
+
+    fn next(&mut self) {
+        DatabaseIterator::next(self)
+    }
+}
+
+/// A storage which keeps all records in memory, for worlds that are built
+/// or tested without touching the disk.
+#[derive(Default)]
+pub struct MemoryStorage {
+    // iterators share the records, a write while they are in use copies
+    // them
+    records: RwLock<Arc<Records>>,
+}
+
+type Records = BTreeMap<Vec<u8>, Vec<u8>>;
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// Returns all records in the order of their keys.
+    pub fn into_records(self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let records = self.records.into_inner();
+        Arc::try_unwrap(records).unwrap_or_else(|records| (*records).clone())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.records.read().get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut records = self.records.write();
+        Arc::make_mut(&mut records).insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut records = self.records.write();
+        Arc::make_mut(&mut records).remove(key);
+        Ok(())
+    }
+
+    fn write(&self, ops: &[BatchOp], _sync: bool) -> Result<()> {
+        // other threads see either none or all of the changes
+        let mut records = self.records.write();
+        let records = Arc::make_mut(&mut records);
+        for op in ops {
+            match op {
+                BatchOp::Put { key, value } => records.insert(key.clone(), value.clone()),
+                BatchOp::Delete { key } => records.remove(key),
+            };
+        }
+
+        Ok(())
+    }
+
+    fn iter(&self) -> Box<dyn StorageIterator + '_> {
+        let records = self.records.read().clone();
+        Box::new(MemoryIterator::new(records, |_| MemoryCursor {
+            iter: None,
+            current: None,
+        }))
+    }
+}
+
+// Like the iterators of LevelDB, the iterator sees the records as they were
+// when it was made, so the storage can be changed while it is iterated
+// over.
+#[self_referencing]
+struct MemoryIterator {
+    records: Arc<Records>,
+    #[borrows(records)]
+    #[covariant]
+    cursor: MemoryCursor<'this>,
+}
+
+struct MemoryCursor<'a> {
+    // `None` until the iterator is moved to the first record
+    iter: Option<btree_map::Iter<'a, Vec<u8>, Vec<u8>>>,
+    current: Option<(&'a Vec<u8>, &'a Vec<u8>)>,
+}
+
+impl MemoryIterator {
+    fn record(&self) -> (&[u8], &[u8]) {
+        let (key, value) = self
+            .borrow_cursor()
+            .current
+            .expect("iterator is not at a record");
+        (key, value)
+    }
+}
+
+impl StorageIterator for MemoryIterator {
+    fn seek_to_first(&mut self) {
+        self.with_mut(|fields| {
+            let mut iter = fields.records.iter();
+            fields.cursor.current = iter.next();
+            fields.cursor.iter = Some(iter);
+        });
+    }
+
+    fn valid(&self) -> bool {
+        self.borrow_cursor().current.is_some()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.record().0
+    }
+
+    fn value(&self) -> &[u8] {
+        self.record().1
+    }
+
+    fn next(&mut self) {
+        self.with_cursor_mut(|cursor| {
+            if cursor.current.is_some() {
+                cursor.current = cursor.iter.as_mut().and_then(|iter| iter.next());
+            }
+        });
+    }
+}
+
+// A storage in memory that remembers the number of records changed by each
+// write, so tests can check what is written.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct CountingStorage {
+    records: MemoryStorage,
+    pub(crate) writes: std::sync::Arc<parking_lot::Mutex<Vec<usize>>>,
+}
+
+#[cfg(test)]
+impl Storage for CountingStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.records.get(key)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.writes.lock().push(1);
+        self.records.put(key, value)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        self.writes.lock().push(1);
+        self.records.delete(key)
+    }
+
+    fn write(&self, ops: &[BatchOp], sync: bool) -> Result<()> {
+        self.writes.lock().push(ops.len());
+        self.records.write(ops, sync)
+    }
+
+    fn iter(&self) -> Box<dyn StorageIterator + '_> {
+        self.records.iter()
+    }
+}
+
+fn open_database(path: &Path) -> Result<Database> {
+    let mut options = Options::default();
+    options.compression = Compression::ZlibRaw;
+
+    Ok(Database::open(path, options)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_iterator_sees_the_records_it_started_with() {
+        let storage = MemoryStorage::new();
+        storage.put(b"a", b"1").unwrap();
+        storage.put(b"b", b"2").unwrap();
+
+        let mut iter = storage.iter();
+        assert!(!iter.valid());
+        iter.seek_to_first();
+        storage.delete(b"b").unwrap();
+        storage.put(b"c", b"3").unwrap();
+
+        let mut seen = Vec::new();
+        while iter.valid() {
+            seen.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        assert_eq!(
+            seen,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec())
+            ]
+        );
+
+        drop(iter);
+        let records = storage.into_records();
+        assert_eq!(records.keys().collect::<Vec<_>>(), vec![b"a", b"c"]);
+    }
+
+    #[test]
+    fn copy_leaves_out_lock_and_is_removed() {
+        let dir = std::env::temp_dir().join(format!("mcworld-db-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("LOCK"), b"").unwrap();
+        fs::write(dir.join("CURRENT"), b"MANIFEST-000001\n").unwrap();
+
+        let copy = TempCopy::of_database(&dir).unwrap();
+        let path = copy.path.clone();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(path.join("CURRENT").is_file());
+        assert!(!path.join("LOCK").exists());
+        drop(copy);
+        assert!(!path.exists());
+    }
+}
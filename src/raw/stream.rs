@@ -0,0 +1,225 @@
+use crate::error::*;
+use crate::pos::*;
+use crate::raw::pos::SubchunkPos;
+use crate::raw::scan::{parse_record_key, RecordKind};
+use crate::raw::storage::StorageIterator;
+use crate::raw::subchunk::Subchunk;
+use crate::raw::world::{decode_subchunk, RawWorld};
+
+/// Which records a stream yields.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct StreamOptions {
+    /// Only yield the records of chunks in this dimension.
+    pub dimension: Option<Dimension>,
+    /// Also yield the chunk records other than subchunks, without decoding
+    /// them.
+    pub other_records: bool,
+}
+
+#[derive(Debug)]
+pub enum Record {
+    Subchunk(SubchunkPos, Subchunk),
+    /// A chunk record other than a subchunk, as it is stored.
+    Other {
+        pos: ChunkPos,
+        kind: RecordKind,
+        data: Vec<u8>,
+    },
+}
+
+/// Walks over the database once, decoding the records as they are read.
+/// A record that cannot be decoded gives an error, after which the stream
+/// continues with the next record.
+pub struct RecordStream<'a> {
+    iter: Box<dyn StorageIterator + 'a>,
+    options: StreamOptions,
+    lenient: bool,
+}
+
+impl<'a> Iterator for RecordStream<'a> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Result<Record>> {
+        while self.iter.valid() {
+            let parsed = parse_record_key(self.iter.key());
+
+            let record = match parsed {
+                Some((pos, kind)) if self.wanted(pos, kind) => Some(match kind {
+                    RecordKind::Subchunk(y) => {
+                        let pos = pos.subchunk_pos(y);
+                        decode_subchunk(&pos, self.iter.value(), self.lenient)
+                            .map(|sc| Record::Subchunk(pos, sc))
+                    }
+                    _ => Ok(Record::Other {
+                        pos,
+                        kind,
+                        data: self.iter.value().to_vec(),
+                    }),
+                }),
+                _ => None,
+            };
+
+            self.iter.next();
+
+            if record.is_some() {
+                return record;
+            }
+        }
+
+        None
+    }
+}
+
+impl<'a> RecordStream<'a> {
+    fn wanted(&self, pos: ChunkPos, kind: RecordKind) -> bool {
+        let in_dimension = match self.options.dimension {
+            Some(dimension) => pos.dimension == dimension,
+            None => true,
+        };
+        let subchunk = matches!(kind, RecordKind::Subchunk(_));
+
+        in_dimension && (subchunk || self.options.other_records)
+    }
+}
+
+impl RawWorld {
+    /// Streams the chunk records of the world in the order they are stored
+    /// in, with every record read only once. This is much faster than
+    /// looking up the subchunks one by one when going over the whole world.
+    pub fn stream_records(&self, options: StreamOptions) -> RecordStream {
+        RecordStream {
+            iter: self.iter_records(),
+            options,
+            lenient: self.is_lenient(),
+        }
+    }
+
+    /// Streams the subchunks of the world, optionally of a single
+    /// dimension. See `stream_records`.
+    pub fn stream_subchunks(
+        &self,
+        dimension: Option<Dimension>,
+    ) -> impl Iterator<Item = Result<(SubchunkPos, Subchunk)>> + '_ {
+        let options = StreamOptions {
+            dimension,
+            other_records: false,
+        };
+
+        self.stream_records(options)
+            .filter_map(|record| match record {
+                Ok(Record::Subchunk(pos, sc)) => Some(Ok((pos, sc))),
+                Ok(Record::Other { .. }) => None,
+                Err(e) => Some(Err(e)),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw::encode::Encode;
+    use crate::raw::pos::{ChunkKey, ENTITY_PREFIX};
+    use crate::raw::scan::air_subchunk;
+    use crate::raw::version::TargetVersion;
+
+    fn chunk(x: i32, dimension: Dimension) -> ChunkPos {
+        ChunkPos { x, z: 0, dimension }
+    }
+
+    fn key<K: Encode<Error = Error>>(key: K) -> Vec<u8> {
+        let mut buf = Vec::new();
+        key.encode(&mut buf).unwrap();
+        buf
+    }
+
+    fn world() -> RawWorld {
+        let sc = air_subchunk(TargetVersion::DataValues);
+        let world = RawWorld::in_memory();
+        for &pos in &[chunk(0, Dimension::Overworld), chunk(1, Dimension::Nether)] {
+            world.save_subchunk(&pos.subchunk_pos(0), &sc).unwrap();
+            world
+                .put_bytes(
+                    &key(ChunkKey {
+                        pos,
+                        tag: ENTITY_PREFIX,
+                    }),
+                    b"entities",
+                )
+                .unwrap();
+        }
+        // records that do not belong to a chunk are never streamed
+        world.put_bytes(b"~local_player", b"player").unwrap();
+
+        world
+    }
+
+    // the chunk and kind of each record, in the order they were streamed
+    fn streamed(world: &RawWorld, options: StreamOptions) -> Vec<(ChunkPos, RecordKind)> {
+        world
+            .stream_records(options)
+            .map(|record| match record.unwrap() {
+                Record::Subchunk(pos, _) => {
+                    let chunk = ChunkPos {
+                        x: pos.x,
+                        z: pos.z,
+                        dimension: pos.dimension,
+                    };
+                    (chunk, RecordKind::Subchunk(pos.subchunk))
+                }
+                Record::Other { pos, kind, data } => {
+                    assert_eq!(data, b"entities");
+                    (pos, kind)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn records_are_filtered() {
+        let world = world();
+        let overworld = chunk(0, Dimension::Overworld);
+        let nether = chunk(1, Dimension::Nether);
+
+        let all = StreamOptions {
+            dimension: None,
+            other_records: true,
+        };
+        assert_eq!(
+            streamed(&world, all),
+            vec![
+                (overworld, RecordKind::Subchunk(0)),
+                (overworld, RecordKind::Entities),
+                (nether, RecordKind::Subchunk(0)),
+                (nether, RecordKind::Entities),
+            ]
+        );
+
+        let nether_subchunks = StreamOptions {
+            dimension: Some(Dimension::Nether),
+            other_records: false,
+        };
+        assert_eq!(
+            streamed(&world, nether_subchunks),
+            vec![(nether, RecordKind::Subchunk(0))]
+        );
+
+        let subchunks: Vec<_> = world
+            .stream_subchunks(Some(Dimension::Overworld))
+            .map(|sc| sc.unwrap().0)
+            .collect();
+        assert_eq!(subchunks, vec![overworld.subchunk_pos(0)]);
+    }
+
+    #[test]
+    fn stream_continues_after_an_error() {
+        let world = world();
+        let broken = chunk(0, Dimension::Overworld);
+        let broken_key = key(broken.subchunk_pos(1));
+        world.put_bytes(&broken_key, &[8, 1, 0xff]).unwrap();
+
+        let results: Vec<_> = world.stream_subchunks(None).collect();
+        assert_eq!(results.len(), 3);
+        assert!(results[1].is_err());
+        assert!(results[2].is_ok());
+    }
+}
@@ -1,15 +1,83 @@
+use super::network::*;
 use super::*;
+use crate::error::{DecodeError, DecodeErrorKind, Error};
+use crate::state::{legacy_name, StateValue};
 use byteorder::{LittleEndian, ReadBytesExt};
-use nbt::{Blob, Value};
-use std::io::Read;
+use fnv::FnvHashMap;
+use nbt::Value;
+use std::collections::HashMap;
+use std::io::{self, Read};
+
+const CHUNK_SIZE: usize = 4096;
+
+pub(crate) const TAG_END: u8 = 0x00;
+pub(crate) const TAG_SHORT: u8 = 0x02;
+pub(crate) const TAG_STRING: u8 = 0x08;
+pub(crate) const TAG_COMPOUND: u8 = 0x0a;
+
+// the fields of a palette entry that are understood
+const PALETTE_ENTRY_FIELDS: [&str; 4] = ["name", "states", "version", "val"];
+
+// keeps track of the number of bytes read, so errors can point to the
+// offset of the malformed data
+struct CountingReader<R> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+// keeps a copy of everything that is read, so unknown data can be stored
+// exactly as it was read
+struct RecordingReader<'a, R> {
+    inner: R,
+    recorded: &'a mut Vec<u8>,
+}
+
+impl<'a, R: Read> Read for RecordingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.recorded.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
 
 pub struct Decoder<'a, T: 'a> {
-    reader: &'a mut T,
+    reader: CountingReader<&'a mut T>,
+    encoding: Encoding<'a>,
+    lenient: bool,
+    // problems with the storages as they were stored, which cannot be seen
+    // once they are decoded
+    issues: Vec<ValidationIssue>,
 }
 
 impl<'a, T: 'a> Decoder<'a, T> {
     pub fn new(reader: &'a mut T) -> Self {
-        Decoder { reader }
+        Decoder::with_encoding(reader, Encoding::Disk)
+    }
+
+    pub fn with_encoding(reader: &'a mut T, encoding: Encoding<'a>) -> Self {
+        Decoder {
+            reader: CountingReader {
+                inner: reader,
+                offset: 0,
+            },
+            encoding,
+            lenient: false,
+            issues: Vec::new(),
+        }
+    }
+
+    /// In lenient mode, palette entry fields and storages that are not
+    /// understood are kept instead of being dropped or rejected.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
     }
 }
 
@@ -17,36 +85,180 @@ impl<'a, T> Decoder<'a, T>
 where
     T: Read,
 {
+    /// Decodes a subchunk. Any error is reported as a `DecodeError` holding
+    /// the offset at which decoding failed.
     pub fn decode_chunk(&mut self) -> Result<Subchunk> {
+        let subchunk = self
+            .decode_chunk_at_offset()
+            .map_err(|e| DecodeError::from_error(e, self.reader.offset))?;
+
+        // duplicate palette entries are accepted, the game itself writes them
+        // at times
+        let mut report = subchunk.validate();
+        report.issues.append(&mut self.issues);
+        if report.is_corrupt() {
+            return Err(DecodeError::new(DecodeErrorKind::Invalid(report)).into());
+        }
+
+        Ok(subchunk)
+    }
+
+    fn decode_chunk_at_offset(&mut self) -> Result<Subchunk> {
         let version = self.reader.read_u8()?;
-        assert_eq!(version, 8);
 
-        let num_storages = self.reader.read_u8()?;
+        let num_storages = match version {
+            // versions before the palette was introduced store numeric block
+            // IDs and data values directly
+            0 | 2..=7 => {
+                return Ok(Subchunk {
+                    version,
+                    block_storages: vec![self.decode_legacy_storage()?],
+                    opaque_storages: None,
+                });
+            }
+            1 => 1,
+            8 => self.reader.read_u8()?,
+            _ => return Err(DecodeError::new(DecodeErrorKind::UnsupportedVersion(version)).into()),
+        };
 
         let mut storages = Vec::new();
-        for _ in 0..num_storages {
-            storages.push(self.decode_storage()?);
+        let mut opaque_storages = None;
+        for i in 0..num_storages {
+            let format = self.reader.read_u8()?;
+
+            if self.lenient && !self.is_known_format(format) {
+                let mut data = vec![format];
+                self.reader.read_to_end(&mut data)?;
+
+                opaque_storages = Some(OpaqueStorages {
+                    count: num_storages - i,
+                    data,
+                });
+                break;
+            }
+
+            let storage = self.decode_storage(format)?;
+            storage.validate_bits_per_block(usize::from(i), format >> 1, &mut self.issues);
+            storages.push(storage);
         }
 
         Ok(Subchunk {
+            version,
             block_storages: storages,
+            opaque_storages,
         })
     }
 
-    fn decode_storage(&mut self) -> Result<BlockStorage> {
-        let format = self.reader.read_u8()?;
+    fn is_known_format(&self, format: u8) -> bool {
+        let runtime = format & 1 == 1;
+        let bits_per_block = format >> 1;
+
+        let runtime_known = match self.encoding {
+            Encoding::Network(_) => true,
+            Encoding::Disk | Encoding::NetworkNbt => !runtime,
+        };
+
+        runtime_known && BITS_PER_BLOCK_OPTIONS.contains(&bits_per_block)
+    }
+
+    fn decode_legacy_storage(&mut self) -> Result<BlockStorage> {
+        let mut ids = vec![0u8; CHUNK_SIZE];
+        self.reader.read_exact(&mut ids)?;
+
+        // data values are stored as nibbles, two to a byte
+        let mut data = vec![0u8; CHUNK_SIZE / 2];
+        self.reader.read_exact(&mut data)?;
+
+        // some versions store light data after the blocks, which newer
+        // versions compute on load
+        self.reader.read_to_end(&mut Vec::new())?;
+
+        let mut mapping = FnvHashMap::default();
+        let mut blocks = Vec::with_capacity(CHUNK_SIZE);
+        let mut palette = Vec::new();
+
+        for (i, id) in ids.iter().enumerate() {
+            let nibbles = data[i / 2];
+            let val = if i % 2 == 0 {
+                nibbles & 0x0f
+            } else {
+                nibbles >> 4
+            };
+
+            let index = match mapping.get(&(*id, val)) {
+                Some(index) => *index,
+                None => {
+                    let name = match legacy_name(*id) {
+                        Some(name) => name,
+                        None => {
+                            let kind = DecodeErrorKind::UnknownBlockId(*id);
+                            return Err(DecodeError::new(kind).into());
+                        }
+                    };
+
+                    let index = palette.len() as u16;
+                    palette.push(PaletteEntry {
+                        name: name.to_owned(),
+                        properties: BlockProperties::Val(u16::from(val)),
+                        extra_fields: Vec::new(),
+                    });
+                    mapping.insert((*id, val), index);
+                    index
+                }
+            };
+
+            blocks.push(index);
+        }
+
+        Ok(BlockStorage { blocks, palette })
+    }
+
+    fn decode_storage(&mut self, format: u8) -> Result<BlockStorage> {
         let network = 0b0000_0001 & format;
-        assert_eq!(network, 0);
-        let bits_per_block = u32::from(0b1111_1110 & format) >> 1;
+        let bits_per_block = (0b1111_1110 & format) >> 1;
+
+        if !BITS_PER_BLOCK_OPTIONS.contains(&bits_per_block) {
+            let kind = DecodeErrorKind::UnsupportedBitsPerBlock(bits_per_block);
+            return Err(DecodeError::new(kind).into());
+        }
+        let bits_per_block = u32::from(bits_per_block);
 
         let blocks = self.decode_blocks(bits_per_block)?;
-        let palette = self.decode_palette()?;
+
+        // uniform storages have a single palette entry, so the number of
+        // entries is not stored for them
+        let num_entries = if bits_per_block == 0 {
+            1
+        } else {
+            match self.encoding {
+                Encoding::Disk => self.reader.read_u32::<LittleEndian>()?,
+                _ => read_var_i32(&mut self.reader)? as u32,
+            }
+        };
+
+        // in the network form, the least significant bit of the format
+        // indicates whether the palette consists of runtime IDs
+        let palette = if network == 0 {
+            match self.encoding {
+                Encoding::Disk => self.decode_palette(num_entries)?,
+                _ => self.decode_network_palette(num_entries)?,
+            }
+        } else {
+            match self.encoding {
+                Encoding::Network(table) => self.decode_runtime_palette(num_entries, table)?,
+                _ => return Err(DecodeError::new(DecodeErrorKind::MissingRuntimeIds).into()),
+            }
+        };
 
         Ok(BlockStorage { blocks, palette })
     }
 
     fn decode_blocks(&mut self, bits_per_block: u32) -> Result<Vec<u16>> {
-        const CHUNK_SIZE: usize = 4096;
+        // a uniform storage has no words, all blocks refer to the only
+        // palette entry
+        if bits_per_block == 0 {
+            return Ok(vec![0; CHUNK_SIZE]);
+        }
 
         let mut blocks = Vec::new();
         while blocks.len() < CHUNK_SIZE {
@@ -58,9 +270,8 @@ where
         Ok(blocks)
     }
 
-    fn decode_palette(&mut self) -> Result<Vec<PaletteEntry>> {
+    fn decode_palette(&mut self, num_entries: u32) -> Result<Vec<PaletteEntry>> {
         let mut palette = Vec::new();
-        let num_entries = self.reader.read_u32::<LittleEndian>()?;
 
         for _ in 0..num_entries {
             let entry = self.decode_palette_entry()?;
@@ -71,20 +282,221 @@ where
     }
 
     fn decode_palette_entry(&mut self) -> Result<PaletteEntry> {
-        let blob = Blob::from_reader(self.reader)?;
-        let name = match blob["name"] {
-            Value::String(ref s) => s.clone(),
-            _ => panic!("no name field"),
+        let (fields, extra_fields) = read_palette_compound(&mut self.reader)?;
+        let extra_fields = if self.lenient {
+            Some(extra_fields)
+        } else {
+            None
+        };
+        palette_entry_from_fields(fields, extra_fields)
+    }
+
+    fn decode_network_palette(&mut self, num_entries: u32) -> Result<Vec<PaletteEntry>> {
+        let mut palette = Vec::new();
+
+        for _ in 0..num_entries {
+            let fields = read_network_compound(&mut self.reader)?;
+            let extra_fields = if self.lenient { Some(Vec::new()) } else { None };
+            palette.push(palette_entry_from_fields(fields, extra_fields)?);
+        }
+
+        Ok(palette)
+    }
+
+    fn decode_runtime_palette(
+        &mut self,
+        num_entries: u32,
+        table: &dyn RuntimeIdTable,
+    ) -> Result<Vec<PaletteEntry>> {
+        let mut palette = Vec::new();
+
+        for _ in 0..num_entries {
+            let runtime_id = read_var_i32(&mut self.reader)?;
+            let entry = match table.palette_entry(runtime_id as u32) {
+                Some(entry) => entry.clone(),
+                None => {
+                    let kind = DecodeErrorKind::UnknownRuntimeId(runtime_id);
+                    return Err(DecodeError::new(kind).into());
+                }
+            };
+            palette.push(entry);
+        }
+
+        Ok(palette)
+    }
+}
+
+// Fields which are left over are added to the extra fields, or dropped if
+// there are no extra fields to keep.
+fn palette_entry_from_fields(
+    mut fields: HashMap<String, Value>,
+    extra_fields: Option<Vec<u8>>,
+) -> Result<PaletteEntry> {
+    let name = match fields.remove("name") {
+        Some(Value::String(s)) => s,
+        Some(v) => return Err(unexpected_tag(TAG_STRING, &v)),
+        None => return Err(DecodeError::new(DecodeErrorKind::MissingField("name")).into()),
+    };
+
+    // newer versions store block states, older versions store a data
+    // value instead
+    let properties = if let Some(states) = fields.remove("states") {
+        let version = match fields.remove("version") {
+            Some(Value::Int(v)) => Some(v),
+            _ => None,
         };
-        let val = match blob["val"] {
-            Value::Short(i) => i,
-            _ => panic!("no val field"),
+
+        BlockProperties::States {
+            states: decode_states(states)?,
+            version,
+        }
+    } else {
+        match fields.remove("val") {
+            // data values above the range of a short stand in for states
+            // that have no data value, see `BlockTable`
+            Some(Value::Short(val)) if val >= 0 => BlockProperties::Val(val as u16),
+            Some(Value::Short(val)) => {
+                return Err(DecodeError::new(DecodeErrorKind::NegativeDataValue(val)).into());
+            }
+            Some(v) => return Err(unexpected_tag(TAG_SHORT, &v)),
+            None => return Err(DecodeError::new(DecodeErrorKind::MissingField("states")).into()),
+        }
+    };
+
+    let extra_fields = match extra_fields {
+        Some(mut extra_fields) => {
+            for (name, value) in &fields {
+                write_field(&mut extra_fields, name, value)?;
+            }
+            extra_fields
+        }
+        None => Vec::new(),
+    };
+
+    Ok(PaletteEntry {
+        name,
+        properties,
+        extra_fields,
+    })
+}
+
+// Reads a palette entry, returning the fields that are understood together
+// with the raw NBT of the other fields.
+fn read_palette_compound<R: Read>(reader: &mut R) -> Result<(HashMap<String, Value>, Vec<u8>)> {
+    expect_compound_tag(reader)?;
+
+    // the name of the root tag is not used
+    Value::from_reader(TAG_STRING, reader)?;
+
+    let mut fields = HashMap::new();
+    let mut extra_fields = Vec::new();
+    loop {
+        let field_tag = reader.read_u8()?;
+        if field_tag == TAG_END {
+            break;
+        }
+
+        // the tag and name are recorded in case the field is not understood
+        let mut header = vec![field_tag];
+        let mut recorder = RecordingReader {
+            inner: &mut *reader,
+            recorded: &mut header,
         };
-        Ok(PaletteEntry {
-            name,
-            val: val as u16,
-        })
+        let name = match Value::from_reader(TAG_STRING, &mut recorder)? {
+            Value::String(name) => name,
+            v => return Err(unexpected_tag(TAG_STRING, &v)),
+        };
+
+        if PALETTE_ENTRY_FIELDS.contains(&name.as_str()) {
+            let value = Value::from_reader(field_tag, reader)?;
+            fields.insert(name, value);
+        } else {
+            extra_fields.extend_from_slice(&header);
+            let mut recorder = RecordingReader {
+                inner: &mut *reader,
+                recorded: &mut extra_fields,
+            };
+            Value::from_reader(field_tag, &mut recorder)?;
+        }
+    }
+
+    Ok((fields, extra_fields))
+}
+
+// Reads fields stored as raw NBT, such as the extra fields of a palette
+// entry.
+pub(crate) fn read_fields(mut data: &[u8]) -> Result<Vec<(String, Value)>> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let tag = data.read_u8()?;
+        let name = match Value::from_reader(TAG_STRING, &mut data)? {
+            Value::String(name) => name,
+            v => return Err(unexpected_tag(TAG_STRING, &v)),
+        };
+        let value = Value::from_reader(tag, &mut data)?;
+        fields.push((name, value));
+    }
+
+    Ok(fields)
+}
+
+pub(crate) fn read_compound<R: Read>(reader: &mut R) -> Result<HashMap<String, Value>> {
+    expect_compound_tag(reader)?;
+
+    // the name of the root tag is not used
+    Value::from_reader(TAG_STRING, reader)?;
+
+    match Value::from_reader(TAG_COMPOUND, reader)? {
+        Value::Compound(fields) => Ok(fields),
+        v => Err(unexpected_tag(TAG_COMPOUND, &v)),
+    }
+}
+
+pub(crate) fn expect_compound_tag<R: Read>(reader: &mut R) -> Result<()> {
+    let found = reader.read_u8()?;
+    if found != TAG_COMPOUND {
+        let kind = DecodeErrorKind::UnexpectedTag {
+            expected: TAG_COMPOUND,
+            found,
+        };
+        return Err(DecodeError::new(kind).into());
+    }
+
+    Ok(())
+}
+
+pub(crate) fn unexpected_tag(expected: u8, value: &Value) -> Error {
+    let kind = DecodeErrorKind::UnexpectedTag {
+        expected,
+        found: value.id(),
+    };
+    DecodeError::new(kind).into()
+}
+
+fn decode_states(value: Value) -> Result<States> {
+    let fields = match value {
+        Value::Compound(fields) => fields,
+        v => return Err(unexpected_tag(TAG_COMPOUND, &v)),
+    };
+
+    let mut states = States::new();
+    for (name, value) in fields {
+        let value = match value {
+            Value::Byte(b) => StateValue::Byte(b),
+            Value::Int(i) => StateValue::Int(i),
+            Value::String(s) => StateValue::String(s),
+            v => {
+                let kind = DecodeErrorKind::BadStateType {
+                    state: name,
+                    tag: v.id(),
+                };
+                return Err(DecodeError::new(kind).into());
+            }
+        };
+        states.insert(name, value);
     }
+
+    Ok(states)
 }
 
 fn unpack_word(mut w: u32, bits_per_block: u32, output: &mut Vec<u16>) {
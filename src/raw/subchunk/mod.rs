@@ -1,14 +1,46 @@
 mod deserialize;
+mod network;
 mod serialize;
+mod validate;
 
 use crate::error::Result;
+use crate::raw::version::TargetVersion;
+use crate::state::States;
 pub use deserialize::*;
+pub use network::{Encoding, RuntimeIdTable, RuntimeIds};
 pub use serialize::*;
+pub use validate::*;
 use std::io::{Read, Write};
 
+/// The newest subchunk format version that can be read and written.
+pub const LATEST_SUBCHUNK_VERSION: u8 = 8;
+
+/// The block state version written into palette entries by the newest
+/// format, corresponding to Minecraft 1.16.
+pub const LATEST_BLOCK_VERSION: i32 = 17_825_808;
+
+// the numbers of bits per block that a block storage can use
+pub(crate) const BITS_PER_BLOCK_OPTIONS: [u8; 9] = [0, 1, 2, 3, 4, 5, 6, 8, 16];
+
 #[derive(Debug, Clone)]
 pub struct Subchunk {
+    // format version the subchunk is stored in, subchunks in a version
+    // without a palette are written using the newest version
+    pub version: u8,
     pub block_storages: Vec<BlockStorage>,
+    /// Storages following `block_storages` in a format that is not
+    /// understood. Only kept by lenient decoding.
+    pub opaque_storages: Option<OpaqueStorages>,
+}
+
+/// Storages in an unknown format, kept as they were read so they can be
+/// written back unchanged. The end of an unknown storage cannot be found, so
+/// this holds all data from the first unknown storage onwards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpaqueStorages {
+    /// The number of storages in the data.
+    pub count: u8,
+    pub data: Vec<u8>,
 }
 
 #[derive(Debug, Clone)]
@@ -20,7 +52,20 @@ pub struct BlockStorage {
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct PaletteEntry {
     pub name: String,
-    pub val: u16,
+    pub properties: BlockProperties,
+    /// Fields of the entry that are not understood, as the raw NBT of the
+    /// tagged fields. Only kept by lenient decoding.
+    pub extra_fields: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum BlockProperties {
+    // legacy data value, used before block states were stored on disk
+    Val(u16),
+    States {
+        states: States,
+        version: Option<i32>,
+    },
 }
 
 impl Subchunk {
@@ -29,8 +74,48 @@ impl Subchunk {
         decoder.decode_chunk()
     }
 
+    /// Reads a subchunk, keeping the parts that are not understood instead
+    /// of rejecting or dropping them. Writing the subchunk back leaves those
+    /// parts unchanged.
+    pub fn deserialize_lenient<T: Read>(reader: &mut T) -> Result<Subchunk> {
+        let mut decoder = Decoder::new(reader);
+        decoder.set_lenient(true);
+        decoder.decode_chunk()
+    }
+
+    /// Writes a subchunk in a form every version of the game that reads
+    /// its format understands.
     pub fn serialize<T: Write>(&self, writer: &mut T) -> Result<()> {
         let mut encoder = Encoder::new(writer);
         encoder.encode_chunk(self)
     }
+
+    /// Like `serialize`, but uses the more compact forms the given version
+    /// of the game understands.
+    pub fn serialize_for<T: Write>(&self, writer: &mut T, target: TargetVersion) -> Result<()> {
+        let mut encoder = Encoder::new(writer);
+        encoder.set_uniform_storages(target.uniform_storages());
+        encoder.encode_chunk(self)
+    }
+
+    /// Reads a subchunk in the network form. Storages with runtime ID
+    /// palettes are resolved using the given table.
+    pub fn deserialize_network<T: Read>(
+        reader: &mut T,
+        table: &dyn RuntimeIdTable,
+    ) -> Result<Subchunk> {
+        let mut decoder = Decoder::with_encoding(reader, Encoding::Network(table));
+        decoder.decode_chunk()
+    }
+
+    /// Writes a subchunk in the network form, with palettes of runtime IDs
+    /// from the given table.
+    pub fn serialize_network<T: Write>(
+        &self,
+        writer: &mut T,
+        table: &dyn RuntimeIdTable,
+    ) -> Result<()> {
+        let mut encoder = Encoder::with_encoding(writer, Encoding::Network(table));
+        encoder.encode_chunk(self)
+    }
 }
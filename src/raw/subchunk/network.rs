@@ -0,0 +1,529 @@
+use super::*;
+use crate::error::{DecodeError, DecodeErrorKind};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::bail;
+use fnv::FnvHashMap;
+use nbt::Value;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+/// Maps palette entries to the runtime IDs used to refer to blocks in the
+/// network protocol. The IDs depend on the version of the game, so the
+/// table has to be supplied by the caller.
+pub trait RuntimeIdTable {
+    fn runtime_id(&self, entry: &PaletteEntry) -> Option<u32>;
+    fn palette_entry(&self, runtime_id: u32) -> Option<&PaletteEntry>;
+}
+
+/// A runtime ID table that assigns IDs in the order of a list of palette
+/// entries, such as the block palette sent by a server.
+pub struct RuntimeIds {
+    entries: Vec<PaletteEntry>,
+    ids: FnvHashMap<PaletteEntry, u32>,
+}
+
+impl RuntimeIds {
+    pub fn new(entries: Vec<PaletteEntry>) -> RuntimeIds {
+        let ids = entries
+            .iter()
+            .enumerate()
+            .map(|(i, e)| (e.clone(), i as u32))
+            .collect();
+
+        RuntimeIds { entries, ids }
+    }
+}
+
+impl RuntimeIdTable for RuntimeIds {
+    fn runtime_id(&self, entry: &PaletteEntry) -> Option<u32> {
+        self.ids.get(entry).cloned()
+    }
+
+    fn palette_entry(&self, runtime_id: u32) -> Option<&PaletteEntry> {
+        self.entries.get(runtime_id as usize)
+    }
+}
+
+/// The form a subchunk is serialized in.
+#[derive(Copy, Clone)]
+pub enum Encoding<'a> {
+    /// The form used to store subchunks in the world database.
+    Disk,
+    /// The network form, with palettes of runtime IDs looked up in the
+    /// given table.
+    Network(&'a dyn RuntimeIdTable),
+    /// The network form, with palettes of network NBT.
+    NetworkNbt,
+}
+
+pub(crate) fn read_var_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut result = 0u32;
+
+    // a 32-bit number takes at most 5 groups of 7 bits
+    for i in 0..5 {
+        let b = reader.read_u8()?;
+        result |= u32::from(b & 0x7f) << (7 * i);
+
+        if b & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+
+    Err(DecodeError::new(DecodeErrorKind::VarintTooLong).into())
+}
+
+pub(crate) fn read_var_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut result = 0u64;
+
+    for i in 0..10 {
+        let b = reader.read_u8()?;
+        result |= u64::from(b & 0x7f) << (7 * i);
+
+        if b & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+
+    Err(DecodeError::new(DecodeErrorKind::VarintTooLong).into())
+}
+
+pub(crate) fn read_var_i32<R: Read>(reader: &mut R) -> Result<i32> {
+    let n = read_var_u32(reader)?;
+    Ok((n >> 1) as i32 ^ -((n & 1) as i32))
+}
+
+pub(crate) fn read_var_i64<R: Read>(reader: &mut R) -> Result<i64> {
+    let n = read_var_u64(reader)?;
+    Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+}
+
+pub(crate) fn write_var_u32<W: Write>(writer: &mut W, n: u32) -> Result<()> {
+    write_var_u64(writer, u64::from(n))
+}
+
+pub(crate) fn write_var_u64<W: Write>(writer: &mut W, mut n: u64) -> Result<()> {
+    loop {
+        let b = (n & 0x7f) as u8;
+        n >>= 7;
+
+        if n == 0 {
+            writer.write_u8(b)?;
+            return Ok(());
+        }
+
+        writer.write_u8(b | 0x80)?;
+    }
+}
+
+pub(crate) fn write_var_i32<W: Write>(writer: &mut W, n: i32) -> Result<()> {
+    write_var_u32(writer, ((n << 1) ^ (n >> 31)) as u32)
+}
+
+pub(crate) fn write_var_i64<W: Write>(writer: &mut W, n: i64) -> Result<()> {
+    write_var_u64(writer, ((n << 1) ^ (n >> 63)) as u64)
+}
+
+// Lists and compounds nested deeper than this are rejected, as the data may
+// come from anywhere and each level of nesting takes up stack space. The
+// game uses the same limit.
+const MAX_NETWORK_DEPTH: usize = 512;
+
+// Network NBT is little-endian NBT in which string lengths are varints and
+// ints, longs and lengths of arrays and lists are zigzag varints.
+
+pub(crate) fn read_network_compound<R: Read>(reader: &mut R) -> Result<HashMap<String, Value>> {
+    expect_compound_tag(reader)?;
+
+    // the name of the root tag is not used
+    read_network_string(reader)?;
+
+    match read_network_value(reader, TAG_COMPOUND, 0)? {
+        Value::Compound(fields) => Ok(fields),
+        v => Err(unexpected_tag(TAG_COMPOUND, &v)),
+    }
+}
+
+pub(crate) fn write_network_compound<W: Write>(
+    writer: &mut W,
+    fields: &HashMap<String, Value>,
+) -> Result<()> {
+    writer.write_u8(TAG_COMPOUND)?;
+    write_network_string(writer, "")?;
+    write_network_fields(writer, fields)
+}
+
+fn read_network_string<R: Read>(reader: &mut R) -> Result<String> {
+    let len = read_var_u32(reader)?;
+
+    // do not trust the length enough to allocate it up front
+    let mut buf = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut buf)?;
+    if buf.len() != len as usize {
+        return Err(DecodeError::new(DecodeErrorKind::Truncated).into());
+    }
+
+    Ok(String::from_utf8(buf)?)
+}
+
+fn read_network_len<R: Read>(reader: &mut R) -> Result<usize> {
+    let len = read_var_i32(reader)?;
+    if len < 0 {
+        return Err(DecodeError::new(DecodeErrorKind::NegativeLength(len)).into());
+    }
+
+    Ok(len as usize)
+}
+
+// reads a value of the given tag, which is nested in `depth` lists and
+// compounds
+fn read_network_value<R: Read>(reader: &mut R, tag: u8, depth: usize) -> Result<Value> {
+    if depth > MAX_NETWORK_DEPTH {
+        let kind = DecodeErrorKind::NestedTooDeep(MAX_NETWORK_DEPTH);
+        return Err(DecodeError::new(kind).into());
+    }
+
+    let value = match tag {
+        0x01 => Value::Byte(reader.read_i8()?),
+        0x02 => Value::Short(reader.read_i16::<LittleEndian>()?),
+        0x03 => Value::Int(read_var_i32(reader)?),
+        0x04 => Value::Long(read_var_i64(reader)?),
+        0x05 => Value::Float(reader.read_f32::<LittleEndian>()?),
+        0x06 => Value::Double(reader.read_f64::<LittleEndian>()?),
+        0x07 => {
+            let len = read_network_len(reader)?;
+            let mut values = Vec::new();
+            for _ in 0..len {
+                values.push(reader.read_i8()?);
+            }
+            Value::ByteArray(values)
+        }
+        TAG_STRING => Value::String(read_network_string(reader)?),
+        0x09 => {
+            let element_tag = reader.read_u8()?;
+            let len = read_network_len(reader)?;
+            let mut values = Vec::new();
+            for _ in 0..len {
+                values.push(read_network_value(reader, element_tag, depth + 1)?);
+            }
+            Value::List(values)
+        }
+        TAG_COMPOUND => {
+            let mut fields = HashMap::new();
+            loop {
+                let field_tag = reader.read_u8()?;
+                if field_tag == TAG_END {
+                    break;
+                }
+
+                let name = read_network_string(reader)?;
+                let value = read_network_value(reader, field_tag, depth + 1)?;
+                fields.insert(name, value);
+            }
+            Value::Compound(fields)
+        }
+        0x0b => {
+            let len = read_network_len(reader)?;
+            let mut values = Vec::new();
+            for _ in 0..len {
+                values.push(read_var_i32(reader)?);
+            }
+            Value::IntArray(values)
+        }
+        0x0c => {
+            let len = read_network_len(reader)?;
+            let mut values = Vec::new();
+            for _ in 0..len {
+                values.push(read_var_i64(reader)?);
+            }
+            Value::LongArray(values)
+        }
+        _ => return Err(DecodeError::new(DecodeErrorKind::UnknownTag(tag)).into()),
+    };
+
+    Ok(value)
+}
+
+fn write_network_string<W: Write>(writer: &mut W, s: &str) -> Result<()> {
+    write_var_u32(writer, s.len() as u32)?;
+    writer.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn write_network_fields<W: Write>(writer: &mut W, fields: &HashMap<String, Value>) -> Result<()> {
+    // sort the fields so the output does not depend on the hash map order
+    let mut names: Vec<&String> = fields.keys().collect();
+    names.sort();
+
+    for name in names {
+        let value = &fields[name];
+        writer.write_u8(value.id())?;
+        write_network_string(writer, name)?;
+        write_network_value(writer, value)?;
+    }
+
+    writer.write_u8(TAG_END)?;
+    Ok(())
+}
+
+fn write_network_value<W: Write>(writer: &mut W, value: &Value) -> Result<()> {
+    match value {
+        Value::Byte(b) => writer.write_i8(*b)?,
+        Value::Short(s) => writer.write_i16::<LittleEndian>(*s)?,
+        Value::Int(i) => write_var_i32(writer, *i)?,
+        Value::Long(l) => write_var_i64(writer, *l)?,
+        Value::Float(f) => writer.write_f32::<LittleEndian>(*f)?,
+        Value::Double(d) => writer.write_f64::<LittleEndian>(*d)?,
+        Value::ByteArray(values) => {
+            write_var_i32(writer, values.len() as i32)?;
+            for b in values {
+                writer.write_i8(*b)?;
+            }
+        }
+        Value::String(s) => write_network_string(writer, s)?,
+        Value::List(values) => {
+            // empty lists have no element type
+            let element_tag = values.first().map(|v| v.id()).unwrap_or(TAG_END);
+            writer.write_u8(element_tag)?;
+            write_var_i32(writer, values.len() as i32)?;
+            for v in values {
+                if v.id() != element_tag {
+                    bail!("list elements have different types");
+                }
+                write_network_value(writer, v)?;
+            }
+        }
+        Value::Compound(fields) => write_network_fields(writer, fields)?,
+        Value::IntArray(values) => {
+            write_var_i32(writer, values.len() as i32)?;
+            for i in values {
+                write_var_i32(writer, *i)?;
+            }
+        }
+        Value::LongArray(values) => {
+            write_var_i32(writer, values.len() as i32)?;
+            for l in values {
+                write_var_i64(writer, *l)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn zigzag_varints() {
+        let cases: &[(i32, &[u8])] = &[
+            (0, &[0x00]),
+            (-1, &[0x01]),
+            (1, &[0x02]),
+            (-64, &[0x7f]),
+            (64, &[0x80, 0x01]),
+            (1 << 27, &[0x80, 0x80, 0x80, 0x80, 0x01]),
+            (i32::MAX, &[0xfe, 0xff, 0xff, 0xff, 0x0f]),
+            (i32::MIN, &[0xff, 0xff, 0xff, 0xff, 0x0f]),
+        ];
+
+        for &(n, bytes) in cases {
+            let mut data = Vec::new();
+            write_var_i32(&mut data, n).unwrap();
+            assert_eq!(data, bytes, "{}", n);
+            assert_eq!(read_var_i32(&mut &data[..]).unwrap(), n);
+        }
+
+        for &n in &[0, -1, i64::from(i32::MIN) - 1, i64::MAX, i64::MIN] {
+            let mut data = Vec::new();
+            write_var_i64(&mut data, n).unwrap();
+            assert_eq!(read_var_i64(&mut &data[..]).unwrap(), n);
+        }
+
+        // a sixth byte does not fit in 32 bits
+        let too_long = [0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        let e = read_var_u32(&mut &too_long[..]).unwrap_err();
+        assert_eq!(decode_error_kind(e), DecodeErrorKind::VarintTooLong);
+    }
+
+    fn decode_error_kind(e: Error) -> DecodeErrorKind {
+        e.downcast::<DecodeError>().unwrap().kind
+    }
+
+    #[test]
+    fn network_nbt_round_trip() {
+        let mut inner = HashMap::new();
+        inner.insert("long".to_owned(), Value::Long(-1 << 40));
+        inner.insert(
+            "ints".to_owned(),
+            Value::IntArray(vec![i32::MIN, 0, i32::MAX]),
+        );
+        inner.insert("longs".to_owned(), Value::LongArray(vec![i64::MIN, 7]));
+
+        let mut fields = HashMap::new();
+        fields.insert("byte".to_owned(), Value::Byte(-3));
+        fields.insert("short".to_owned(), Value::Short(-300));
+        fields.insert("int".to_owned(), Value::Int(-70_000));
+        fields.insert("double".to_owned(), Value::Double(0.5));
+        fields.insert("bytes".to_owned(), Value::ByteArray(vec![1, -1]));
+        fields.insert(
+            "name".to_owned(),
+            Value::String("minecraft:stone".to_owned()),
+        );
+        fields.insert("empty".to_owned(), Value::List(Vec::new()));
+        fields.insert("list".to_owned(), Value::List(vec![Value::Compound(inner)]));
+
+        let mut data = Vec::new();
+        write_network_compound(&mut data, &fields).unwrap();
+        let mut reader = &data[..];
+        assert_eq!(read_network_compound(&mut reader).unwrap(), fields);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        // a compound holding a compound, and so on
+        let mut data = vec![TAG_COMPOUND, 0];
+        for _ in 0..100_000 {
+            data.extend_from_slice(&[TAG_COMPOUND, 0]);
+        }
+
+        let e = read_network_compound(&mut &data[..]).unwrap_err();
+        assert_eq!(
+            decode_error_kind(e),
+            DecodeErrorKind::NestedTooDeep(MAX_NETWORK_DEPTH)
+        );
+    }
+
+    #[test]
+    fn malformed_network_nbt_errors() {
+        // a compound with a field of tag 13, which does not exist
+        let data = [TAG_COMPOUND, 0, 13, 1, b'a', 0];
+        let e = read_network_compound(&mut &data[..]).unwrap_err();
+        assert_eq!(decode_error_kind(e), DecodeErrorKind::UnknownTag(13));
+
+        // a byte array with a length of -1
+        let data = [TAG_COMPOUND, 0, 7, 1, b'a', 1, 0];
+        let e = read_network_compound(&mut &data[..]).unwrap_err();
+        assert_eq!(decode_error_kind(e), DecodeErrorKind::NegativeLength(-1));
+
+        // the root is a string rather than a compound
+        let data = [TAG_STRING, 0, 0];
+        let e = read_network_compound(&mut &data[..]).unwrap_err();
+        assert_eq!(
+            decode_error_kind(e),
+            DecodeErrorKind::UnexpectedTag {
+                expected: TAG_COMPOUND,
+                found: TAG_STRING,
+            }
+        );
+    }
+
+    fn entry(name: &str, val: u16) -> PaletteEntry {
+        PaletteEntry {
+            name: name.to_owned(),
+            properties: BlockProperties::Val(val),
+            extra_fields: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn subchunk_round_trip() {
+        let ids = RuntimeIds::new(vec![
+            entry("minecraft:air", 0),
+            entry("minecraft:stone", 0),
+            entry("minecraft:stone", 1),
+            entry("minecraft:water", 0),
+        ]);
+        let sc = Subchunk {
+            version: 8,
+            block_storages: vec![
+                BlockStorage {
+                    blocks: (0..4096).map(|i| (i % 3) as u16).collect(),
+                    palette: vec![
+                        entry("minecraft:stone", 1),
+                        entry("minecraft:air", 0),
+                        entry("minecraft:stone", 0),
+                    ],
+                },
+                BlockStorage {
+                    blocks: vec![0; 4096],
+                    palette: vec![entry("minecraft:water", 0)],
+                },
+            ],
+            opaque_storages: None,
+        };
+
+        let mut data = Vec::new();
+        sc.serialize_network(&mut data, &ids).unwrap();
+        // storages with runtime IDs have the lowest bit of their format set
+        assert_eq!(data[2] & 1, 1);
+
+        let read = Subchunk::deserialize_network(&mut &data[..], &ids).unwrap();
+        assert_eq!(read.block_storages.len(), 2);
+        for (read, written) in read.block_storages.iter().zip(&sc.block_storages) {
+            assert_eq!(read.blocks, written.blocks);
+            assert_eq!(read.palette, written.palette);
+        }
+
+        // blocks missing from the table cannot be written
+        let sc = Subchunk {
+            block_storages: vec![BlockStorage {
+                blocks: vec![0; 4096],
+                palette: vec![entry("minecraft:dirt", 0)],
+            }],
+            ..sc
+        };
+        assert!(sc.serialize_network(&mut Vec::new(), &ids).is_err());
+    }
+
+    #[test]
+    fn network_nbt_palette_round_trip() {
+        let sc = Subchunk {
+            version: 8,
+            block_storages: vec![BlockStorage {
+                blocks: (0..4096).map(|i| (i % 2) as u16).collect(),
+                palette: vec![entry("minecraft:air", 0), entry("minecraft:wool", 14)],
+            }],
+            opaque_storages: None,
+        };
+
+        let mut data = Vec::new();
+        Encoder::with_encoding(&mut data, Encoding::NetworkNbt)
+            .encode_chunk(&sc)
+            .unwrap();
+
+        let mut reader = &data[..];
+        let read = Decoder::with_encoding(&mut reader, Encoding::NetworkNbt)
+            .decode_chunk()
+            .unwrap();
+        assert_eq!(read.block_storages[0].blocks, sc.block_storages[0].blocks);
+        assert_eq!(read.block_storages[0].palette, sc.block_storages[0].palette);
+    }
+
+    #[test]
+    fn zero_bit_storage_has_no_palette_length() {
+        let ids = RuntimeIds::new(vec![entry("minecraft:air", 0), entry("minecraft:dirt", 0)]);
+        let sc = Subchunk {
+            version: 8,
+            block_storages: vec![BlockStorage {
+                blocks: vec![0; 4096],
+                palette: vec![entry("minecraft:dirt", 0)],
+            }],
+            opaque_storages: None,
+        };
+
+        let mut data = Vec::new();
+        let mut encoder = Encoder::with_encoding(&mut data, Encoding::Network(&ids));
+        encoder.set_uniform_storages(true);
+        encoder.encode_chunk(&sc).unwrap();
+
+        // the format is followed by the runtime ID right away
+        assert_eq!(data, vec![8, 1, 1, 2]);
+
+        let read = Subchunk::deserialize_network(&mut &data[..], &ids).unwrap();
+        assert_eq!(read.block_storages[0].blocks, vec![0; 4096]);
+        assert_eq!(read.block_storages[0].palette, sc.block_storages[0].palette);
+    }
+}
@@ -1,16 +1,37 @@
+use super::network::*;
 use super::*;
+use crate::state::StateValue;
 use byteorder::{LittleEndian, WriteBytesExt};
-use nbt::{Blob, Value};
+use failure::bail;
+use nbt::Value;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io::Write;
 
 pub struct Encoder<'a, T: 'a> {
     writer: &'a mut T,
+    encoding: Encoding<'a>,
+    uniform_storages: bool,
 }
 
 impl<'a, T> Encoder<'a, T> {
     pub fn new(writer: &'a mut T) -> Self {
-        Encoder { writer }
+        Encoder::with_encoding(writer, Encoding::Disk)
+    }
+
+    pub fn with_encoding(writer: &'a mut T, encoding: Encoding<'a>) -> Self {
+        Encoder {
+            writer,
+            encoding,
+            uniform_storages: false,
+        }
+    }
+
+    /// Writes storages of a single kind of block with zero bits per block.
+    /// Only newer versions of the game read these, see
+    /// `TargetVersion::uniform_storages`.
+    pub fn set_uniform_storages(&mut self, uniform_storages: bool) {
+        self.uniform_storages = uniform_storages;
     }
 }
 
@@ -19,37 +40,95 @@ where
     T: Write,
 {
     pub fn encode_chunk(&mut self, subchunk: &Subchunk) -> Result<()> {
-        const VERSION: u8 = 8;
-        self.writer.write_u8(VERSION)?;
+        // a subchunk which could not be read back is never written, the
+        // checks below rely on this
+        let report = subchunk.validate();
+        if report.is_corrupt() {
+            return Err(report.into());
+        }
 
-        let num_storages = subchunk.block_storages.len();
-        self.writer.write_u8(num_storages as u8)?;
+        let num_opaque = match subchunk.opaque_storages {
+            Some(ref opaque) => {
+                // opaque storages are kept in the form they were read in
+                match self.encoding {
+                    Encoding::Disk => {}
+                    _ => bail!("storages in an unknown format can only be written to disk"),
+                }
+
+                usize::from(opaque.count)
+            }
+            None => 0,
+        };
+        let num_storages = subchunk.block_storages.len() + num_opaque;
+        if num_storages > usize::from(u8::MAX) {
+            bail!("subchunk has too many storages ({})", num_storages);
+        }
+
+        if subchunk.version == 1 {
+            // version 1 has room for a single storage only
+            if num_storages != 1 {
+                bail!("subchunk version 1 must have exactly one storage");
+            }
+
+            self.writer.write_u8(1)?;
+        } else {
+            // subchunks in formats without a palette are upgraded when they
+            // are written
+            self.writer.write_u8(LATEST_SUBCHUNK_VERSION)?;
+            self.writer.write_u8(num_storages as u8)?;
+        }
 
         for s in &subchunk.block_storages {
             self.encode_storage(s)?;
         }
 
+        if let Some(ref opaque) = subchunk.opaque_storages {
+            self.writer.write_all(&opaque.data)?;
+        }
+
         Ok(())
     }
 
     fn encode_storage(&mut self, storage: &BlockStorage) -> Result<()> {
-        let bits_per_block = bits_per_block(storage.palette.len());
-
-        // the least significant bit of the format indicates whether
-        // we are serializing for the network. we are not, hence we
-        // leave it at 0.
-        let format = bits_per_block << 1;
+        let bits_per_block = bits_per_block(storage.palette.len(), self.uniform_storages);
+
+        // the least significant bit of the format indicates whether the
+        // palette consists of runtime IDs, which are only used in the
+        // network form.
+        let runtime = match self.encoding {
+            Encoding::Network(_) => 1,
+            Encoding::Disk | Encoding::NetworkNbt => 0,
+        };
+        let format = bits_per_block << 1 | runtime;
         self.writer.write_u8(format)?;
 
         self.encode_blocks(&storage.blocks, bits_per_block)?;
-        self.encode_palette(&storage.palette)?;
+
+        // uniform storages have a single palette entry, so the number of
+        // entries is left out for them
+        if bits_per_block != 0 {
+            let num_entries = storage.palette.len();
+            match self.encoding {
+                Encoding::Disk => self.writer.write_u32::<LittleEndian>(num_entries as u32)?,
+                _ => write_var_i32(self.writer, num_entries as i32)?,
+            }
+        }
+
+        match self.encoding {
+            Encoding::Disk => self.encode_palette(&storage.palette)?,
+            Encoding::Network(table) => self.encode_runtime_palette(&storage.palette, table)?,
+            Encoding::NetworkNbt => self.encode_network_palette(&storage.palette)?,
+        }
 
         Ok(())
     }
 
     fn encode_blocks(&mut self, blocks: &[u16], bits_per_block: u8) -> Result<()> {
-        const CHUNK_SIZE: usize = 4096;
-        assert_eq!(blocks.len(), CHUNK_SIZE);
+        // a uniform storage has no words, every block refers to the only
+        // palette entry
+        if bits_per_block == 0 {
+            return Ok(());
+        }
 
         let blocks_per_word = 32 / bits_per_block;
 
@@ -64,9 +143,6 @@ where
     }
 
     fn encode_palette(&mut self, palette: &[PaletteEntry]) -> Result<()> {
-        let num_entries = palette.len();
-        self.writer.write_u32::<LittleEndian>(num_entries as u32)?;
-
         for e in palette {
             self.encode_palette_entry(e)?;
         }
@@ -75,27 +151,147 @@ where
     }
 
     fn encode_palette_entry(&mut self, entry: &PaletteEntry) -> Result<()> {
-        let mut nbt = Blob::new();
-        nbt.insert("name".to_owned(), Value::String(entry.name.clone()))
-            .unwrap();
-        nbt.insert(
-            "val".to_owned(),
-            Value::Short(entry.val.try_into().unwrap()),
-        )
-        .unwrap();
+        self.writer.write_u8(TAG_COMPOUND)?;
+        Value::String(String::new()).to_writer(self.writer)?;
+
+        // fields are written in order of their names, so the output does not
+        // depend on the hash map order
+        let fields = palette_entry_fields(entry)?;
+        let mut names: Vec<&String> = fields.keys().collect();
+        names.sort();
 
-        nbt.to_writer(self.writer)?;
+        for name in names {
+            write_field(self.writer, name, &fields[name])?;
+        }
+
+        // fields that are not understood are written back as they were read
+        self.writer.write_all(&entry.extra_fields)?;
+
+        self.writer.write_u8(TAG_END)?;
+
+        Ok(())
+    }
+
+    fn encode_network_palette(&mut self, palette: &[PaletteEntry]) -> Result<()> {
+        for e in palette {
+            let mut fields = palette_entry_fields(e)?;
+            fields.extend(read_fields(&e.extra_fields)?);
+
+            write_network_compound(self.writer, &fields)?;
+        }
+
+        Ok(())
+    }
+
+    fn encode_runtime_palette(
+        &mut self,
+        palette: &[PaletteEntry],
+        table: &dyn RuntimeIdTable,
+    ) -> Result<()> {
+        for e in palette {
+            let runtime_id = match table.runtime_id(e) {
+                Some(id) => id,
+                None => bail!("no runtime ID for block {}", e.name),
+            };
+            write_var_i32(self.writer, runtime_id as i32)?;
+        }
 
         Ok(())
     }
 }
 
-fn bits_per_block(num_palette_entries: usize) -> u8 {
-    const OPTIONS: [u8; 8] = [1u8, 2, 3, 4, 5, 6, 8, 16];
+fn palette_entry_fields(entry: &PaletteEntry) -> Result<HashMap<String, Value>> {
+    let mut fields = HashMap::new();
+    fields.insert("name".to_owned(), Value::String(entry.name.clone()));
+
+    match entry.properties {
+        BlockProperties::Val(val) => {
+            // data values are stored as a signed short
+            let val = match val.try_into() {
+                Ok(val) => val,
+                Err(_) => bail!("data value {} of block {} is too large", val, entry.name),
+            };
+            fields.insert("val".to_owned(), Value::Short(val));
+        }
+        BlockProperties::States {
+            ref states,
+            version,
+        } => {
+            fields.insert("states".to_owned(), encode_states(states));
+
+            if let Some(version) = version {
+                fields.insert("version".to_owned(), Value::Int(version));
+            }
+        }
+    }
 
+    Ok(fields)
+}
+
+// Writes a tagged field as NBT. Compounds are written with their fields in
+// order of their names, like palette entries.
+pub(crate) fn write_field<W: Write>(writer: &mut W, name: &str, value: &Value) -> Result<()> {
+    writer.write_u8(value.id())?;
+    Value::String(name.to_owned()).to_writer(writer)?;
+    write_value(writer, value)
+}
+
+fn write_value<W: Write>(writer: &mut W, value: &Value) -> Result<()> {
+    match value {
+        Value::Compound(fields) => {
+            let mut names: Vec<&String> = fields.keys().collect();
+            names.sort();
+
+            for name in names {
+                write_field(writer, name, &fields[name])?;
+            }
+            writer.write_u8(TAG_END)?;
+        }
+        Value::List(values) => {
+            // lists may hold compounds, so the elements are written one by
+            // one. empty lists have no element type
+            let element_tag = values.first().map(|v| v.id()).unwrap_or(TAG_END);
+            writer.write_u8(element_tag)?;
+            Value::Int(values.len() as i32).to_writer(writer)?;
+
+            for v in values {
+                if v.id() != element_tag {
+                    bail!("list elements have different types");
+                }
+                write_value(writer, v)?;
+            }
+        }
+        _ => value.to_writer(writer)?,
+    }
+
+    Ok(())
+}
+
+fn encode_states(states: &States) -> Value {
+    let fields: HashMap<String, Value> = states
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                StateValue::Byte(b) => Value::Byte(*b),
+                StateValue::Int(i) => Value::Int(*i),
+                StateValue::String(s) => Value::String(s.clone()),
+            };
+            (name.clone(), value)
+        })
+        .collect();
+
+    Value::Compound(fields)
+}
+
+fn bits_per_block(num_palette_entries: usize, uniform_storages: bool) -> u8 {
     // find the smallest number of bits per block that would be big
-    // enough to hold all possibilities
-    for o in &OPTIONS {
+    // enough to hold all possibilities, a palette with a single entry
+    // needs no bits at all if the target can read that
+    for o in &BITS_PER_BLOCK_OPTIONS {
+        if *o == 0 && !uniform_storages {
+            continue;
+        }
+
         let max_entries = 1usize << o;
 
         if num_palette_entries <= max_entries {
@@ -103,22 +299,18 @@ fn bits_per_block(num_palette_entries: usize) -> u8 {
         }
     }
 
-    panic!("palette too big");
+    // validation limits palettes to the entries 16 bits can address
+    unreachable!("palette too big");
 }
 
 fn pack_word(blocks: &[u16], bits_per_block: u8) -> u32 {
     let mut result = !0u32;
 
-    // check that the blocks will fit inside a word
-    let blocks_per_word = 32 / bits_per_block;
-    assert!(blocks.len() <= usize::from(blocks_per_word));
-
-    let max_index = 1u16 << bits_per_block;
+    // the storage has been validated, so the blocks fit inside a word and
+    // every index fits in the bits available
+    debug_assert!(blocks.len() <= usize::from(32 / bits_per_block));
 
     for b in blocks.iter().rev() {
-        // check that the index is not too high
-        assert!(*b < max_index);
-
         // create a space for the new block
         result <<= bits_per_block;
 
@@ -128,3 +320,85 @@ fn pack_word(blocks: &[u16], bits_per_block: u8) -> u32 {
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{DecodeError, DecodeErrorKind};
+
+    fn uniform_subchunk() -> Subchunk {
+        Subchunk {
+            version: 8,
+            block_storages: vec![BlockStorage {
+                blocks: vec![0; 4096],
+                palette: vec![PaletteEntry {
+                    name: "minecraft:stone".to_owned(),
+                    properties: BlockProperties::Val(0),
+                    extra_fields: Vec::new(),
+                }],
+            }],
+            opaque_storages: None,
+        }
+    }
+
+    #[test]
+    fn negative_data_value_is_an_error() {
+        let mut data = Vec::new();
+        uniform_subchunk().serialize(&mut data).unwrap();
+
+        // the name of the val field, which is followed by its value
+        let start = data.windows(3).position(|w| w == b"val").unwrap();
+        let value = start + 3;
+        data[value..value + 2].copy_from_slice(&(-1i16).to_le_bytes());
+
+        for e in &[
+            Subchunk::deserialize(&mut &data[..]).unwrap_err(),
+            Subchunk::deserialize_lenient(&mut &data[..]).unwrap_err(),
+        ] {
+            let e = e.downcast_ref::<DecodeError>().unwrap();
+            assert_eq!(e.kind, DecodeErrorKind::NegativeDataValue(-1));
+        }
+    }
+
+    #[test]
+    fn zero_bits_only_for_targets_that_read_them() {
+        let sc = uniform_subchunk();
+
+        for &(target, bits_per_block) in &[
+            (TargetVersion::BlockStates, 1),
+            (TargetVersion::UniformStorages, 0),
+        ] {
+            let mut data = Vec::new();
+            sc.serialize_for(&mut data, target).unwrap();
+
+            // the version and the number of storages come before the format
+            assert_eq!(data[2] >> 1, bits_per_block);
+
+            let read = Subchunk::deserialize(&mut &data[..]).unwrap();
+            assert_eq!(read.block_storages[0].blocks, sc.block_storages[0].blocks);
+            assert_eq!(read.block_storages[0].palette, sc.block_storages[0].palette);
+        }
+    }
+
+    #[test]
+    fn large_data_value_is_an_error() {
+        let mut sc = uniform_subchunk();
+        sc.block_storages[0].palette[0].properties = BlockProperties::Val(40_000);
+
+        assert!(sc.serialize(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn palette_entry_without_name_is_an_error() {
+        let mut data = Vec::new();
+        uniform_subchunk().serialize(&mut data).unwrap();
+
+        // renaming the field leaves the entry without a name
+        let start = data.windows(4).position(|w| w == b"name").unwrap();
+        data[start..start + 4].copy_from_slice(b"nome");
+
+        let e = Subchunk::deserialize(&mut &data[..]).unwrap_err();
+        let e = e.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!(e.kind, DecodeErrorKind::MissingField("name"));
+    }
+}
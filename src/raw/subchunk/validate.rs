@@ -0,0 +1,282 @@
+use super::*;
+use fnv::FnvHashMap;
+use std::fmt;
+
+const CHUNK_SIZE: usize = 4096;
+
+// indices are stored with at most 16 bits per block
+const MAX_PALETTE_LEN: usize = 1 << 16;
+
+/// A problem with the contents of a block storage. The storage is identified
+/// by its index in the subchunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationIssue {
+    BlockCount {
+        storage: usize,
+        count: usize,
+    },
+    PaletteIndex {
+        storage: usize,
+        block: usize,
+        index: u16,
+        palette_len: usize,
+    },
+    PaletteTooLarge {
+        storage: usize,
+        palette_len: usize,
+    },
+    /// The palette has more entries than the indices of the storage, as it
+    /// was stored, can refer to.
+    PaletteExceedsBits {
+        storage: usize,
+        bits_per_block: u8,
+        palette_len: usize,
+    },
+    DuplicateEntry {
+        storage: usize,
+        first: usize,
+        duplicate: usize,
+    },
+}
+
+impl ValidationIssue {
+    /// Whether the storage cannot be used at all. Duplicate palette entries
+    /// waste space, but the blocks can still be read.
+    pub fn is_corrupt(&self) -> bool {
+        !matches!(self, ValidationIssue::DuplicateEntry { .. })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub fn is_corrupt(&self) -> bool {
+        self.issues.iter().any(ValidationIssue::is_corrupt)
+    }
+}
+
+impl BlockStorage {
+    /// Checks that the storage holds a block for every position in the
+    /// subchunk, that every block refers to an entry in the palette and that
+    /// the palette can be stored.
+    pub fn validate(&self, storage: usize, issues: &mut Vec<ValidationIssue>) {
+        if self.blocks.len() != CHUNK_SIZE {
+            issues.push(ValidationIssue::BlockCount {
+                storage,
+                count: self.blocks.len(),
+            });
+        }
+
+        let palette_len = self.palette.len();
+        if palette_len > MAX_PALETTE_LEN {
+            issues.push(ValidationIssue::PaletteTooLarge {
+                storage,
+                palette_len,
+            });
+        }
+
+        // only report the first bad index, a corrupt storage usually has
+        // thousands of them
+        let bad_index = self
+            .blocks
+            .iter()
+            .enumerate()
+            .find(|(_, index)| usize::from(**index) >= palette_len);
+        if let Some((block, index)) = bad_index {
+            issues.push(ValidationIssue::PaletteIndex {
+                storage,
+                block,
+                index: *index,
+                palette_len,
+            });
+        }
+
+        let mut seen = FnvHashMap::default();
+        for (i, entry) in self.palette.iter().enumerate() {
+            if let Some(first) = seen.insert(entry, i) {
+                issues.push(ValidationIssue::DuplicateEntry {
+                    storage,
+                    first,
+                    duplicate: i,
+                });
+            }
+        }
+    }
+}
+
+impl BlockStorage {
+    /// Checks that every palette entry can be referred to by an index of the
+    /// given number of bits, for a storage that was read with that many bits
+    /// per block. Indices beyond the palette are found by `validate`.
+    pub fn validate_bits_per_block(
+        &self,
+        storage: usize,
+        bits_per_block: u8,
+        issues: &mut Vec<ValidationIssue>,
+    ) {
+        let palette_len = self.palette.len();
+        if palette_len > 1 << bits_per_block {
+            issues.push(ValidationIssue::PaletteExceedsBits {
+                storage,
+                bits_per_block,
+                palette_len,
+            });
+        }
+    }
+}
+
+impl Subchunk {
+    pub fn validate(&self) -> ValidationReport {
+        let mut issues = Vec::new();
+        for (i, storage) in self.block_storages.iter().enumerate() {
+            storage.validate(i, &mut issues);
+        }
+
+        ValidationReport { issues }
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationIssue::BlockCount { storage, count } => {
+                write!(f, "storage {} has {} blocks", storage, count)
+            }
+            ValidationIssue::PaletteIndex {
+                storage,
+                block,
+                index,
+                palette_len,
+            } => write!(
+                f,
+                "block {} of storage {} has index {} in a palette of {} entries",
+                block, storage, index, palette_len
+            ),
+            ValidationIssue::PaletteTooLarge {
+                storage,
+                palette_len,
+            } => write!(
+                f,
+                "palette of storage {} has too many entries ({})",
+                storage, palette_len
+            ),
+            ValidationIssue::PaletteExceedsBits {
+                storage,
+                bits_per_block,
+                palette_len,
+            } => write!(
+                f,
+                "palette of storage {} has {} entries, but its indices have {} bits",
+                storage, palette_len, bits_per_block
+            ),
+            ValidationIssue::DuplicateEntry {
+                storage,
+                first,
+                duplicate,
+            } => write!(
+                f,
+                "palette entries {} and {} of storage {} are the same",
+                first, duplicate, storage
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid subchunk")?;
+
+        for (i, issue) in self.issues.iter().enumerate() {
+            let separator = if i == 0 { ": " } else { ", " };
+            write!(f, "{}{}", separator, issue)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationReport {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(names: &[&str], blocks: Vec<u16>) -> BlockStorage {
+        let palette = names
+            .iter()
+            .map(|name| PaletteEntry {
+                name: (*name).to_owned(),
+                properties: BlockProperties::Val(0),
+                extra_fields: Vec::new(),
+            })
+            .collect();
+
+        BlockStorage { blocks, palette }
+    }
+
+    #[test]
+    fn index_beyond_palette() {
+        let mut blocks = vec![0; CHUNK_SIZE];
+        blocks[10] = 5;
+        let sc = Subchunk {
+            version: 8,
+            block_storages: vec![storage(&["minecraft:air", "minecraft:dirt"], blocks)],
+            opaque_storages: None,
+        };
+
+        let report = sc.validate();
+        assert!(report.is_corrupt());
+        assert_eq!(
+            report.issues,
+            vec![ValidationIssue::PaletteIndex {
+                storage: 0,
+                block: 10,
+                index: 5,
+                palette_len: 2,
+            }]
+        );
+        assert!(sc.serialize(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn palette_beyond_bits_per_block() {
+        let mut blocks = vec![0; CHUNK_SIZE];
+        blocks[0] = 1;
+        let sc = Subchunk {
+            version: 8,
+            block_storages: vec![storage(&["minecraft:aaaa", "minecraft:bbbb"], blocks)],
+            opaque_storages: None,
+        };
+
+        // a storage with one bit per block, whose palette is given a third
+        // entry after it was written
+        let mut data = Vec::new();
+        sc.serialize(&mut data).unwrap();
+        assert_eq!(data[2] >> 1, 1);
+        let palette_start = 3 + CHUNK_SIZE / 32 * 4;
+        data[palette_start..palette_start + 4].copy_from_slice(&3u32.to_le_bytes());
+        let entry_len = (data.len() - palette_start - 4) / 2;
+        let last_entry = data[data.len() - entry_len..].to_vec();
+        data.extend_from_slice(&last_entry);
+
+        let error = Subchunk::deserialize(&mut &data[..]).unwrap_err();
+        let report = match error.downcast::<crate::error::DecodeError>().unwrap().kind {
+            crate::error::DecodeErrorKind::Invalid(report) => report,
+            kind => panic!("unexpected error {:?}", kind),
+        };
+        assert!(report
+            .issues
+            .contains(&ValidationIssue::PaletteExceedsBits {
+                storage: 0,
+                bits_per_block: 1,
+                palette_len: 3,
+            }));
+    }
+}
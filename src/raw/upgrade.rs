@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+
+use crate::error::*;
+use crate::pos::*;
+use crate::raw::subchunk::{BlockProperties, Subchunk, LATEST_BLOCK_VERSION, LATEST_SUBCHUNK_VERSION};
+use crate::raw::world::RawWorld;
+use crate::state::BlockState;
+
+/// The chunk version written by an upgrade, corresponding to Minecraft 1.16.
+pub const LATEST_CHUNK_VERSION: u8 = 21;
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct UpgradeOptions {
+    /// Only report what would be upgraded, without writing anything.
+    pub dry_run: bool,
+}
+
+/// The outcome of upgrading a single chunk.
+#[derive(Debug)]
+pub struct ChunkUpgrade {
+    pub pos: ChunkPos,
+    pub old_version: Option<u8>,
+    /// Number of subchunks that were rewritten, or would have been rewritten
+    /// for a dry run.
+    pub upgraded_subchunks: usize,
+    /// Number of palette entries whose data value has no block state
+    /// equivalent. These are kept as they are.
+    pub unmapped_entries: usize,
+    /// The error that stopped the upgrade of this chunk, if any. Subchunks
+    /// that were upgraded before the error occurred stay upgraded.
+    pub error: Option<Error>,
+}
+
+impl RawWorld {
+    /// Converts all subchunks to the newest subchunk format with block
+    /// states in their palettes, and updates the chunk versions to match.
+    ///
+    /// A failure to upgrade a chunk does not stop the upgrade of the other
+    /// chunks, instead it is recorded in the report for that chunk.
+    pub fn upgrade(&self, options: &UpgradeOptions) -> Result<Vec<ChunkUpgrade>> {
+        // collect the positions first, so the database is not modified while
+        // it is being iterated
+        let mut chunks: BTreeMap<ChunkPos, Vec<u8>> = BTreeMap::new();
+        for pos in self.iter_chunks() {
+            let pos = pos?;
+            let chunk_pos = ChunkPos {
+                x: pos.x,
+                z: pos.z,
+                dimension: pos.dimension,
+            };
+            chunks.entry(chunk_pos).or_default().push(pos.subchunk);
+        }
+
+        let reports = chunks
+            .into_iter()
+            .map(|(pos, subchunks)| self.upgrade_chunk(pos, &subchunks, options))
+            .collect();
+
+        Ok(reports)
+    }
+
+    fn upgrade_chunk(
+        &self,
+        pos: ChunkPos,
+        subchunks: &[u8],
+        options: &UpgradeOptions,
+    ) -> ChunkUpgrade {
+        let mut report = ChunkUpgrade {
+            pos,
+            old_version: None,
+            upgraded_subchunks: 0,
+            unmapped_entries: 0,
+            error: None,
+        };
+
+        if let Err(e) = self.try_upgrade_chunk(&mut report, subchunks, options) {
+            report.error = Some(e);
+        }
+
+        report
+    }
+
+    fn try_upgrade_chunk(
+        &self,
+        report: &mut ChunkUpgrade,
+        subchunks: &[u8],
+        options: &UpgradeOptions,
+    ) -> Result<()> {
+        report.old_version = self.load_chunk_version(&report.pos)?;
+
+        for y in subchunks {
+            let sub_pos = report.pos.subchunk_pos(*y);
+            let mut sc = match self.load_subchunk(&sub_pos)? {
+                Some(sc) => sc,
+                None => continue,
+            };
+
+            let (changed, unmapped) = upgrade_subchunk(&mut sc);
+            report.unmapped_entries += unmapped;
+
+            if changed {
+                report.upgraded_subchunks += 1;
+
+                if !options.dry_run {
+                    self.save_subchunk(&sub_pos, &sc)?;
+                }
+            }
+        }
+
+        let outdated = match report.old_version {
+            Some(v) => v < LATEST_CHUNK_VERSION,
+            None => true,
+        };
+        if outdated && !options.dry_run {
+            self.save_chunk_version(&report.pos, LATEST_CHUNK_VERSION)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Returns whether the subchunk was changed, together with the number of
+// palette entries that could not be converted to block states.
+fn upgrade_subchunk(sc: &mut Subchunk) -> (bool, usize) {
+    let mut changed = sc.version != LATEST_SUBCHUNK_VERSION;
+    let mut unmapped = 0;
+
+    for storage in &mut sc.block_storages {
+        for entry in &mut storage.palette {
+            let val = match entry.properties {
+                BlockProperties::Val(val) => val,
+                BlockProperties::States { .. } => continue,
+            };
+
+            match BlockState::from_legacy(&entry.name, val) {
+                Some(state) => {
+                    entry.properties = BlockProperties::States {
+                        states: state.states,
+                        version: Some(LATEST_BLOCK_VERSION),
+                    };
+                    changed = true;
+                }
+                None => unmapped += 1,
+            }
+        }
+    }
+
+    sc.version = LATEST_SUBCHUNK_VERSION;
+
+    (changed, unmapped)
+}
@@ -0,0 +1,178 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use nbt::Value;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::path::Path;
+
+use crate::error::*;
+use crate::raw::subchunk::{read_compound, BlockProperties, Subchunk, LATEST_BLOCK_VERSION};
+
+/// The version of the game that subchunks are written for. Versions are
+/// ordered from oldest to newest.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TargetVersion {
+    /// A single block storage per subchunk with data values in the palette,
+    /// as used by Minecraft 1.2.13 up to 1.3.
+    SingleStorage,
+    /// Multiple block storages per subchunk with data values in the palette,
+    /// as used by Minecraft 1.3 up to 1.13.
+    DataValues,
+    /// Multiple block storages per subchunk with block states in the
+    /// palette, as used by Minecraft 1.13 up to 1.16. The block states are
+    /// marked with the block version of 1.13.
+    EarlyBlockStates,
+    /// Multiple block storages per subchunk with block states in the
+    /// palette, as used by Minecraft 1.16 up to 1.18. The block states are
+    /// marked with the block version of 1.16.
+    BlockStates,
+    /// Like `BlockStates`, but storages holding a single kind of block are
+    /// written with zero bits per block, as used by Minecraft 1.18 and later.
+    UniformStorages,
+}
+
+// the first chunk versions written by the game versions that introduced the
+// formats, older chunk versions all have a single storage
+const SINGLE_STORAGE_CHUNK_VERSION: u8 = 7;
+const DATA_VALUES_CHUNK_VERSION: u8 = 8;
+const EARLY_BLOCK_STATES_CHUNK_VERSION: u8 = 13;
+const BLOCK_STATES_CHUNK_VERSION: u8 = 18;
+const UNIFORM_STORAGES_CHUNK_VERSION: u8 = 39;
+
+// the block version of Minecraft 1.13.0, which the game stores as one byte
+// for each part of the version number
+const EARLY_BLOCK_VERSION: i32 = 0x010d_0000;
+
+impl TargetVersion {
+    pub const LATEST: TargetVersion = TargetVersion::UniformStorages;
+
+    /// The format used by the given release of the game, such as `(1, 16)`
+    /// for Minecraft 1.16. Releases older than 1.2.13 are mapped to the
+    /// oldest format that can be written.
+    pub fn from_game_version(major: i32, minor: i32) -> TargetVersion {
+        if (major, minor) >= (1, 18) {
+            TargetVersion::UniformStorages
+        } else if (major, minor) >= (1, 16) {
+            TargetVersion::BlockStates
+        } else if (major, minor) >= (1, 13) {
+            TargetVersion::EarlyBlockStates
+        } else if (major, minor) >= (1, 3) {
+            TargetVersion::DataValues
+        } else {
+            TargetVersion::SingleStorage
+        }
+    }
+
+    /// The format used by chunks with the given chunk version.
+    pub fn from_chunk_version(version: u8) -> TargetVersion {
+        if version >= UNIFORM_STORAGES_CHUNK_VERSION {
+            TargetVersion::UniformStorages
+        } else if version >= BLOCK_STATES_CHUNK_VERSION {
+            TargetVersion::BlockStates
+        } else if version >= EARLY_BLOCK_STATES_CHUNK_VERSION {
+            TargetVersion::EarlyBlockStates
+        } else if version >= DATA_VALUES_CHUNK_VERSION {
+            TargetVersion::DataValues
+        } else {
+            TargetVersion::SingleStorage
+        }
+    }
+
+    /// The first chunk version of the format, which is read back as the
+    /// same format by `from_chunk_version`.
+    pub fn chunk_version(self) -> u8 {
+        match self {
+            TargetVersion::SingleStorage => SINGLE_STORAGE_CHUNK_VERSION,
+            TargetVersion::DataValues => DATA_VALUES_CHUNK_VERSION,
+            TargetVersion::EarlyBlockStates => EARLY_BLOCK_STATES_CHUNK_VERSION,
+            TargetVersion::BlockStates => BLOCK_STATES_CHUNK_VERSION,
+            TargetVersion::UniformStorages => UNIFORM_STORAGES_CHUNK_VERSION,
+        }
+    }
+
+    /// The oldest format a subchunk can have been written in. Storages with
+    /// zero bits per block are not kept apart once read, so this is never
+    /// `UniformStorages`.
+    pub(crate) fn of_subchunk(sc: &Subchunk) -> TargetVersion {
+        if sc.version == 1 {
+            return TargetVersion::SingleStorage;
+        }
+
+        let mut target = TargetVersion::DataValues;
+        for entry in sc.block_storages.iter().flat_map(|s| &s.palette) {
+            if let BlockProperties::States { version, .. } = entry.properties {
+                match version {
+                    Some(v) if v > EARLY_BLOCK_VERSION => return TargetVersion::BlockStates,
+                    _ => target = TargetVersion::EarlyBlockStates,
+                }
+            }
+        }
+
+        target
+    }
+
+    pub fn subchunk_version(self) -> u8 {
+        match self {
+            TargetVersion::SingleStorage => 1,
+            _ => 8,
+        }
+    }
+
+    pub fn block_states(self) -> bool {
+        self >= TargetVersion::EarlyBlockStates
+    }
+
+    /// The version palette entries with block states are marked with, or
+    /// `None` if the format has no block states. Newer versions of the game
+    /// upgrade older block states, but not the other way around.
+    pub fn block_version(self) -> Option<i32> {
+        match self {
+            TargetVersion::SingleStorage | TargetVersion::DataValues => None,
+            TargetVersion::EarlyBlockStates => Some(EARLY_BLOCK_VERSION),
+            TargetVersion::BlockStates | TargetVersion::UniformStorages => {
+                Some(LATEST_BLOCK_VERSION)
+            }
+        }
+    }
+
+    /// Whether storages of a single kind of block can be written without
+    /// any bits per block.
+    pub fn uniform_storages(self) -> bool {
+        self >= TargetVersion::UniformStorages
+    }
+}
+
+/// Detects the format of a world from the version of the game that last
+/// opened it, as recorded in the `level.dat` file next to the database
+/// directory. Returns `None` if there is no `level.dat` or if it does not
+/// record the version.
+pub fn detect_target_version(db_path: &Path) -> Result<Option<TargetVersion>> {
+    let level_path = match db_path.parent() {
+        Some(world_path) => world_path.join("level.dat"),
+        None => return Ok(None),
+    };
+
+    let file = match File::open(level_path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = BufReader::new(file);
+
+    // the file starts with the storage version and the length of the data,
+    // neither of which is needed
+    reader.read_u32::<LittleEndian>()?;
+    reader.read_u32::<LittleEndian>()?;
+
+    let fields = read_compound(&mut reader)?;
+    let version = match fields.get("lastOpenedWithVersion") {
+        Some(Value::List(parts)) => parts,
+        _ => return Ok(None),
+    };
+
+    match (version.first(), version.get(1)) {
+        (Some(Value::Int(major)), Some(Value::Int(minor))) => {
+            Ok(Some(TargetVersion::from_game_version(*major, *minor)))
+        }
+        _ => Ok(None),
+    }
+}
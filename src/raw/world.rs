@@ -1,80 +1,269 @@
 use byteorder::{LittleEndian, ReadBytesExt};
-use leveldb::database::iterator::DatabaseIterator;
-use leveldb::database::Database;
-use leveldb::options::{Compression, Options, ReadOptions, WriteOptions};
 use std::io::{Cursor, Read};
 use std::path::Path;
 
 use crate::error::*;
+use crate::error::{DecodeError, DecodeErrorKind};
 use crate::pos::*;
-use crate::raw::encode::encode_into_buffer;
+use crate::raw::encode::{encode_into_buffer, Encode};
+use crate::raw::storage::*;
 use crate::raw::subchunk::Subchunk;
-use crate::raw::pos::{SubchunkPos, SUBCHUNK_PREFIX, SUBCHUNK_KEY_LEN_OVERWORLD, SUBCHUNK_KEY_LEN_OTHER};
+use crate::raw::version::TargetVersion;
+use crate::raw::pos::{
+    ChunkKey, SubchunkPos, SUBCHUNK_KEY_LEN_OTHER, SUBCHUNK_KEY_LEN_OVERWORLD, SUBCHUNK_PREFIX,
+    VERSION_OLD_PREFIX, VERSION_PREFIX,
+};
+
+// size of the keys and values `RawWorld::copy_to` writes at a time
+const COPY_BATCH_SIZE: usize = 4 << 20;
+
+/// A group of changes to a world that are written all at once by
+/// `RawWorld::write`, so that the world never holds only part of them.
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    /// Stores the subchunk in a form the given version of the game reads,
+    /// see `Subchunk::serialize_for`.
+    pub fn save_subchunk(
+        &mut self,
+        pos: &SubchunkPos,
+        sc: &Subchunk,
+        target: TargetVersion,
+    ) -> Result<()> {
+        let mut serialized = Vec::new();
+        sc.serialize_for(&mut serialized, target)?;
+
+        let mut key_buf = [0u8; 32];
+        let key_slice = encode_into_buffer(pos, &mut key_buf[..])?;
+        self.ops.push(BatchOp::Put {
+            key: key_slice.to_vec(),
+            value: serialized,
+        });
+
+        Ok(())
+    }
+
+    pub fn delete_subchunk(&mut self, pos: &SubchunkPos) -> Result<()> {
+        let mut key_buf = [0u8; 32];
+        let key_slice = encode_into_buffer(pos, &mut key_buf[..])?;
+        self.ops.push(BatchOp::Delete {
+            key: key_slice.to_vec(),
+        });
+
+        Ok(())
+    }
+}
+
+impl Default for WriteBatch {
+    fn default() -> WriteBatch {
+        WriteBatch::new()
+    }
+}
 
 pub struct RawWorld {
-    database: Database,
+    storage: Box<dyn Storage>,
+    lenient: bool,
 }
 
 impl RawWorld {
     pub fn open(path: &Path) -> Result<RawWorld> {
-        let mut options = Options::default();
-        options.compression = Compression::ZlibRaw;
+        let storage = LevelDbStorage::open(path)?;
+        Ok(RawWorld::with_storage(Box::new(storage)))
+    }
 
-        let database = Database::open(path, options)?;
+    /// Opens a world whose records are never changed, any attempt to
+    /// write fails. All reads come from a snapshot of the database taken
+    /// when it is opened.
+    ///
+    /// The database is still opened the normal way, which locks it and may
+    /// write to its files, see `LevelDbSnapshot`. This fails while the game
+    /// has the world open; `open_read_only_copy` works in that case.
+    pub fn open_read_only(path: &Path) -> Result<RawWorld> {
+        let storage = LevelDbSnapshot::open(path)?;
+        Ok(RawWorld::with_storage(Box::new(storage)))
+    }
 
-        Ok(RawWorld { database })
+    /// Opens a copy of the world that can only be read, leaving the files
+    /// of the world untouched. See `LevelDbSnapshot::open_copy`.
+    pub fn open_read_only_copy(path: &Path) -> Result<RawWorld> {
+        let storage = LevelDbSnapshot::open_copy(path)?;
+        Ok(RawWorld::with_storage(Box::new(storage)))
     }
 
-    pub fn load_subchunk(&self, pos: &SubchunkPos) -> Result<Option<Subchunk>> {
-        let mut key_buf = [0u8; 32];
-        let key_slice = encode_into_buffer(pos, &mut key_buf[..])?;
+    /// A world stored in the given storage instead of a LevelDB database.
+    pub fn with_storage(storage: Box<dyn Storage>) -> RawWorld {
+        RawWorld {
+            storage,
+            lenient: false,
+        }
+    }
 
-        let read_options = ReadOptions::default();
-        let maybe_data = self.database.get_bytes(&read_options, key_slice)?;
+    /// An empty world kept in memory, see `MemoryStorage`.
+    pub fn in_memory() -> RawWorld {
+        RawWorld::with_storage(Box::new(MemoryStorage::new()))
+    }
 
-        if let Some(b) = maybe_data {
-            let len = b.len();
-            let mut cursor = Cursor::new(b);
+    pub fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
 
-            let chunk = Subchunk::deserialize(&mut cursor)?;
+    pub fn is_read_only(&self) -> bool {
+        self.storage.is_read_only()
+    }
 
-            // make sure we consume ALL of the data
-            assert_eq!(cursor.position() as usize, len);
+    /// Loads subchunks in lenient mode, keeping the parts which are not
+    /// understood so they are saved back unchanged. See
+    /// `Subchunk::deserialize_lenient`.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
 
-            Ok(Some(chunk))
-        } else {
-            Ok(None)
-        }
+    pub(crate) fn is_lenient(&self) -> bool {
+        self.lenient
     }
 
-    pub fn save_subchunk(&self, pos: &SubchunkPos, sc: &Subchunk) -> Result<()> {
-        let mut key_buf = [0u8; 32];
-        let key_slice = encode_into_buffer(pos, &mut key_buf[..])?;
+    pub fn load_subchunk(&self, pos: &SubchunkPos) -> Result<Option<Subchunk>> {
+        let maybe_data = self.get_value(pos)?;
 
-        let write_options = WriteOptions::default();
+        match maybe_data {
+            Some(b) => Ok(Some(decode_subchunk(pos, &b, self.lenient)?)),
+            None => Ok(None),
+        }
+    }
 
+    pub fn save_subchunk(&self, pos: &SubchunkPos, sc: &Subchunk) -> Result<()> {
         let mut serialized = Vec::new();
         sc.serialize(&mut serialized)?;
 
-        self.database.put(&write_options, key_slice, &serialized)?;
+        self.put_value(pos, &serialized)
+    }
 
-        Ok(())
+    /// Whether the subchunk is stored, without decoding it.
+    pub fn has_subchunk(&self, pos: &SubchunkPos) -> Result<bool> {
+        Ok(self.get_value(pos)?.is_some())
     }
 
     pub fn delete_subchunk(&self, pos: &SubchunkPos) -> Result<()> {
+        self.delete_value(pos)
+    }
+
+    /// Applies all changes in the batch at once. With `sync`, the call only
+    /// returns once the changes have reached the disk, so they survive a
+    /// crash of the system as well.
+    pub fn write(&self, batch: WriteBatch, sync: bool) -> Result<()> {
+        self.storage.write(&batch.ops, sync)
+    }
+
+    /// Writes all records of this world into another one, for example to
+    /// store a world that was built in memory. Records of the other world
+    /// with the same key are replaced, and all of its other records are
+    /// kept, so copying into a world that is not empty merges the two.
+    ///
+    /// The records are written in batches of a few megabytes, so the copy
+    /// never has to fit in memory. If the copy fails, the records written
+    /// so far stay in the other world.
+    pub fn copy_to(&self, other: &RawWorld, sync: bool) -> Result<()> {
+        let mut ops = Vec::new();
+        let mut batch_size = 0;
+
+        let mut iter = self.iter_records();
+        while iter.valid() {
+            batch_size += iter.key().len() + iter.value().len();
+            ops.push(BatchOp::Put {
+                key: iter.key().to_vec(),
+                value: iter.value().to_vec(),
+            });
+            iter.next();
+
+            if batch_size >= COPY_BATCH_SIZE {
+                other.storage.write(&ops, sync)?;
+                ops.clear();
+                batch_size = 0;
+            }
+        }
+
+        other.storage.write(&ops, sync)
+    }
+
+    pub fn load_chunk_version(&self, pos: &ChunkPos) -> Result<Option<u8>> {
+        // older versions of the game store the version under a different key
+        for tag in &[VERSION_PREFIX, VERSION_OLD_PREFIX] {
+            let key = ChunkKey {
+                pos: *pos,
+                tag: *tag,
+            };
+            if let Some(b) = self.get_value(&key)? {
+                return Ok(b.first().cloned());
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn save_chunk_version(&self, pos: &ChunkPos, version: u8) -> Result<()> {
+        let key = ChunkKey {
+            pos: *pos,
+            tag: VERSION_PREFIX,
+        };
+        self.put_value(&key, &[version])?;
+
+        // remove the old key so the game does not see two versions
+        let old_key = ChunkKey {
+            pos: *pos,
+            tag: VERSION_OLD_PREFIX,
+        };
+        self.delete_value(&old_key)
+    }
+
+    fn get_value<K: Encode<Error = Error>>(&self, key: &K) -> Result<Option<Vec<u8>>> {
         let mut key_buf = [0u8; 32];
-        let key_slice = encode_into_buffer(pos, &mut key_buf[..])?;
+        let key_slice = encode_into_buffer(key, &mut key_buf[..])?;
+
+        self.get_bytes(key_slice)
+    }
 
-        let write_options = WriteOptions::default();
+    fn put_value<K: Encode<Error = Error>>(&self, key: &K, value: &[u8]) -> Result<()> {
+        let mut key_buf = [0u8; 32];
+        let key_slice = encode_into_buffer(key, &mut key_buf[..])?;
 
-        self.database.delete(&write_options, key_slice)?;
+        self.put_bytes(key_slice, value)
+    }
 
-        Ok(())
+    fn delete_value<K: Encode<Error = Error>>(&self, key: &K) -> Result<()> {
+        let mut key_buf = [0u8; 32];
+        let key_slice = encode_into_buffer(key, &mut key_buf[..])?;
+
+        self.delete_bytes(key_slice)
+    }
+
+    // access to records by their raw key, for records which have no key type
+    pub(crate) fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.storage.get(key)
+    }
+
+    pub(crate) fn put_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.storage.put(key, value)
+    }
+
+    pub(crate) fn delete_bytes(&self, key: &[u8]) -> Result<()> {
+        self.storage.delete(key)
+    }
+
+    // iterates over all records in the database, not just subchunks
+    pub(crate) fn iter_records(&self) -> Box<dyn StorageIterator + '_> {
+        let mut iter = self.storage.iter();
+        iter.seek_to_first();
+        iter
     }
 
     pub fn iter_chunks(&self) -> SubchunkIterator {
-        let read_options = ReadOptions::default();
-        let dbiter = self.database.iter(&read_options);
+        let dbiter = self.storage.iter();
         SubchunkIterator {
             iter: dbiter,
             state: SubchunkIteratorState::NotStarted,
@@ -85,11 +274,10 @@ impl RawWorld {
 enum SubchunkIteratorState {
     NotStarted,
     Started,
-    Done,
 }
 
 pub struct SubchunkIterator<'a> {
-    iter: DatabaseIterator<'a>,
+    iter: Box<dyn StorageIterator + 'a>,
     state: SubchunkIteratorState,
 }
 
@@ -97,47 +285,55 @@ impl<'a> Iterator for SubchunkIterator<'a> {
     type Item = Result<SubchunkPos>;
 
     fn next(&mut self) -> Option<Result<SubchunkPos>> {
-        match self.state {
-            SubchunkIteratorState::Done => return None,
-            SubchunkIteratorState::NotStarted => {
-                self.iter.seek_to_first();
-                self.state = SubchunkIteratorState::Started;
-            }
-            SubchunkIteratorState::Started => {}
+        if let SubchunkIteratorState::NotStarted = self.state {
+            self.iter.seek_to_first();
+            self.state = SubchunkIteratorState::Started;
         }
 
-        // At this point, we now that the iterator is in the Started
-        // state
-
         loop {
             if !self.iter.valid() {
-                self.state = SubchunkIteratorState::Done;
                 return None;
             }
 
-            let key_slice = self.iter.key();
-            if let Some(res) = try_decode_pos(key_slice) {
-                // If an error occurs while trying to decode the
-                // position key, then mark the iteration as done and
-                // return the error
-                if res.is_err() {
-                    self.state = SubchunkIteratorState::Done;
-                }
+            // a key that cannot be decoded is returned as an error, and the
+            // iteration continues with the next key
+            let res = try_decode_pos(self.iter.key());
+            self.iter.next();
 
-                self.iter.next();
+            if let Some(res) = res {
                 return Some(res);
             }
-
-            // skip keys which do not represent subchunk block data
-            self.iter.next();
         }
     }
 }
 
+pub(crate) fn decode_subchunk(pos: &SubchunkPos, data: &[u8], lenient: bool) -> Result<Subchunk> {
+    let len = data.len();
+    let mut cursor = Cursor::new(data);
+
+    let chunk = if lenient {
+        Subchunk::deserialize_lenient(&mut cursor)
+    } else {
+        Subchunk::deserialize(&mut cursor)
+    };
+    let chunk = chunk.map_err(|e| DecodeError::in_subchunk(e, *pos))?;
+
+    // make sure we consume ALL of the data
+    let consumed = cursor.position();
+    if consumed as usize != len {
+        let kind = DecodeErrorKind::TrailingBytes(len - consumed as usize);
+        let mut error = DecodeError::at_offset(kind, consumed);
+        error.pos = Some(*pos);
+        return Err(error.into());
+    }
+
+    Ok(chunk)
+}
+
 fn try_decode_pos(key: &[u8]) -> Option<Result<SubchunkPos>> {
     // check if the one-to-last element of the key contains the subchunk
     // prefix, otherwise it does not contain block data
-    if key[key.len() - 2] == SUBCHUNK_PREFIX {
+    if key.len() >= 2 && key[key.len() - 2] == SUBCHUNK_PREFIX {
         if key.len() == SUBCHUNK_KEY_LEN_OVERWORLD {
             Some(decode_pos(key, true))
         } else if key.len() == SUBCHUNK_KEY_LEN_OTHER {
@@ -159,7 +355,10 @@ fn decode_pos(key: &[u8], overworld: bool) -> Result<SubchunkPos> {
         match dim {
             1 => Dimension::Nether,
             2 => Dimension::End,
-            _ => panic!("unexpected dimension {} in decode_pos", dim),
+            _ => {
+                let kind = DecodeErrorKind::UnknownDimension(dim);
+                return Err(DecodeError::in_key(kind, key).into());
+            }
         }
     } else {
         Dimension::Overworld
@@ -170,7 +369,10 @@ fn decode_pos(key: &[u8], overworld: bool) -> Result<SubchunkPos> {
     let mut buf = [0u8; 2];
     cursor.read_exact(&mut buf)?;
 
-    assert_eq!(buf[0], SUBCHUNK_PREFIX, "invalid subchunk prefix");
+    if buf[0] != SUBCHUNK_PREFIX {
+        let kind = DecodeErrorKind::BadRecordTag(buf[0]);
+        return Err(DecodeError::in_key(kind, key).into());
+    }
     let subchunk = buf[1];
 
     Ok(SubchunkPos {
@@ -180,3 +382,61 @@ fn decode_pos(key: &[u8], overworld: bool) -> Result<SubchunkPos> {
         dimension,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subchunk_key(x: i32, dimension: u32) -> Vec<u8> {
+        let mut key = Vec::new();
+        key.extend_from_slice(&x.to_le_bytes());
+        key.extend_from_slice(&0i32.to_le_bytes());
+        if dimension != 0 {
+            key.extend_from_slice(&dimension.to_le_bytes());
+        }
+        key.extend_from_slice(&[SUBCHUNK_PREFIX, 0]);
+        key
+    }
+
+    #[test]
+    fn iteration_continues_after_bad_key() {
+        let world = RawWorld::in_memory();
+        world.put_bytes(&subchunk_key(0, 0), &[]).unwrap();
+        // there is no dimension 7
+        world.put_bytes(&subchunk_key(1, 7), &[]).unwrap();
+        world.put_bytes(&subchunk_key(2, 0), &[]).unwrap();
+
+        let results: Vec<_> = world.iter_chunks().collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results.iter().filter(|r| r.is_err()).count(), 1);
+
+        let xs: Vec<_> = results.into_iter().flatten().map(|pos| pos.x).collect();
+        assert_eq!(xs, vec![0, 2]);
+    }
+
+    #[test]
+    fn copy_is_written_in_batches_and_merged() {
+        let world = RawWorld::in_memory();
+        let value = vec![0; 1 << 20];
+        for x in 0..10 {
+            world.put_bytes(&subchunk_key(x, 0), &value).unwrap();
+        }
+
+        let storage = CountingStorage::default();
+        storage.put(b"other", b"kept").unwrap();
+        storage.put(&subchunk_key(0, 0), b"replaced").unwrap();
+        storage.writes.lock().clear();
+        let writes = storage.writes.clone();
+        let other = RawWorld::with_storage(Box::new(storage));
+        world.copy_to(&other, false).unwrap();
+
+        for x in 0..10 {
+            let copied = other.get_bytes(&subchunk_key(x, 0)).unwrap();
+            assert_eq!(copied.unwrap().len(), value.len());
+        }
+        assert_eq!(other.get_bytes(b"other").unwrap().unwrap(), b"kept");
+
+        // about 4 MiB at a time, and the rest at the end
+        assert_eq!(*writes.lock(), vec![4, 4, 2]);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::chunk::Chunk;
+use crate::error::*;
+use crate::pos::*;
+use crate::raw::ScanReport;
+use crate::table::BlockId;
+use crate::world::{BlockLayers, World};
+
+/// A world opened by `World::open_read_only` or
+/// `World::open_read_only_copy`. It has none of the methods that change the
+/// world, and reads from a snapshot of the database taken when it was
+/// opened.
+pub struct ReadOnlyWorld {
+    world: World,
+}
+
+impl ReadOnlyWorld {
+    pub(crate) fn new(world: World) -> ReadOnlyWorld {
+        ReadOnlyWorld { world }
+    }
+
+    /// Limits the number of chunks kept in memory, see
+    /// `World::set_cache_capacity`.
+    pub fn set_cache_capacity(&mut self, capacity: Option<usize>) {
+        self.world.set_cache_capacity(capacity)
+    }
+
+    pub fn iter_chunks<'a>(&'a self) -> impl Iterator<Item = Result<ChunkPos>> + 'a {
+        self.world.iter_chunks()
+    }
+
+    pub fn get_block(&self, pos: &WorldPos) -> Result<Option<BlockLayers>> {
+        self.world.get_block(pos)
+    }
+
+    /// Calls `f` with the chunk at the given position, see
+    /// `World::with_chunk`.
+    pub fn with_chunk<F, R>(&self, pos: ChunkPos, f: F) -> Result<Option<R>>
+    where
+        F: FnOnce(&Chunk) -> R,
+    {
+        self.world.with_chunk(pos, |chunk| f(chunk))
+    }
+
+    /// Returns a copy of the chunk at the given position.
+    pub fn chunk(&self, pos: ChunkPos) -> Result<Option<Chunk>> {
+        self.world.chunk(pos)
+    }
+
+    /// Calls `f` for every chunk in the world on several threads, see
+    /// `World::par_for_each_chunk`.
+    pub fn par_for_each_chunk<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn(&ReadOnlyWorld, ChunkPos) -> Result<()> + Sync,
+    {
+        self.world.par_for_each_chunk(|_, pos| f(self, pos))
+    }
+
+    /// Drops a chunk from memory.
+    pub fn unload_chunk(&self, pos: ChunkPos) -> Result<()> {
+        self.world.unload_chunk(pos)
+    }
+
+    /// Checks every chunk record in the world, see `RawWorld::scan`.
+    pub fn scan(&self) -> Result<ScanReport> {
+        self.world.scan()
+    }
+
+    pub fn block_id(&self, name: &str) -> BlockId {
+        self.world.block_id(name)
+    }
+
+    pub fn block_name(&self, id: BlockId) -> String {
+        self.world.block_name(id)
+    }
+}
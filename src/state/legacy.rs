@@ -0,0 +1,162 @@
+use fnv::FnvHashMap;
+use lazy_static::lazy_static;
+
+use crate::state::{StateValue, States};
+
+const LEGACY_TABLE: &str = include_str!("legacy.txt");
+
+lazy_static! {
+    pub(super) static ref TABLE: LegacyTable = LegacyTable::parse(LEGACY_TABLE);
+}
+
+pub(super) struct LegacyTable {
+    pub by_name: FnvHashMap<String, LegacyBlock>,
+    pub by_id: FnvHashMap<u8, String>,
+}
+
+pub(super) struct LegacyBlock {
+    // a block has at most 16 data values, so a linear search is fine
+    vals: Vec<(u16, States)>,
+}
+
+impl LegacyBlock {
+    pub fn states(&self, val: u16) -> Option<&States> {
+        self.vals
+            .iter()
+            .find(|(v, _)| *v == val)
+            .map(|(_, states)| states)
+    }
+
+    pub fn val(&self, states: &States) -> Option<u16> {
+        self.vals.iter().find(|(_, s)| s == states).map(|(v, _)| *v)
+    }
+}
+
+impl LegacyTable {
+    // The table is bundled with the crate, so a malformed line is a bug in
+    // the crate and not something the caller can recover from.
+    fn parse(text: &str) -> LegacyTable {
+        let mut by_name = FnvHashMap::default();
+        let mut by_id = FnvHashMap::default();
+
+        let mut current: Option<(String, LegacyBlock)> = None;
+
+        for line in text.lines() {
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+
+            if line.starts_with(' ') {
+                let (_, block) = current.as_mut().expect("data value outside of block");
+                block.vals.push(parse_val(line.trim()));
+                continue;
+            }
+
+            // a new block starts, so finish the previous one
+            if let Some((name, block)) = current.take() {
+                by_name.insert(name, block);
+            }
+
+            let mut parts = line.split_whitespace();
+            let id = parts.next().expect("missing block ID");
+            let name = parts.next().expect("missing block name").to_owned();
+
+            if id != "-" {
+                let id: u8 = id.parse().expect("invalid block ID");
+                by_id.insert(id, name.clone());
+            }
+
+            current = Some((name, LegacyBlock { vals: Vec::new() }));
+        }
+
+        if let Some((name, block)) = current.take() {
+            by_name.insert(name, block);
+        }
+
+        LegacyTable { by_name, by_id }
+    }
+}
+
+fn parse_val(line: &str) -> (u16, States) {
+    let mut parts = line.split_whitespace();
+    let val = parts
+        .next()
+        .expect("missing data value")
+        .parse()
+        .expect("invalid data value");
+
+    let mut states = States::new();
+    for state in parts {
+        let mut split = state.splitn(2, '=');
+        let name = split.next().expect("missing state name");
+        let value = split.next().expect("missing state value");
+        states.insert(name.to_owned(), parse_state_value(value));
+    }
+
+    (val, states)
+}
+
+fn parse_state_value(value: &str) -> StateValue {
+    let mut split = value.splitn(2, ':');
+    let kind = split.next().expect("missing state type");
+    let value = split.next().expect("missing state value");
+
+    match kind {
+        "b" => StateValue::Byte(value.parse().expect("invalid byte state")),
+        "i" => StateValue::Int(value.parse().expect("invalid int state")),
+        "s" => StateValue::String(value.to_owned()),
+        _ => panic!("unknown state type {}", kind),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::BlockState;
+
+    #[test]
+    fn every_entry_round_trips() {
+        assert_eq!(TABLE.by_name.len(), 293);
+
+        for (name, block) in &TABLE.by_name {
+            for (val, states) in &block.vals {
+                let state = BlockState::from_legacy(name, *val).unwrap();
+                assert_eq!(&state.states, states);
+                assert_eq!(state.to_legacy(), Some(*val), "{} {}", name, val);
+            }
+        }
+
+        for (id, name) in &TABLE.by_id {
+            let state = BlockState::from_legacy_id(*id, 0);
+            assert_eq!(state.map(|s| s.name), Some(name.clone()));
+        }
+    }
+
+    fn state(name: &str, val: u16) -> States {
+        BlockState::from_legacy(name, val).unwrap().states
+    }
+
+    fn string(value: &str) -> StateValue {
+        StateValue::String(value.to_owned())
+    }
+
+    #[test]
+    fn known_blocks() {
+        assert_eq!(state("minecraft:wool", 14)["color"], string("red"));
+        assert_eq!(
+            BlockState::from_legacy_id(35, 1).unwrap().name,
+            "minecraft:wool"
+        );
+
+        let log = state("minecraft:log", 9);
+        assert_eq!(log["old_log_type"], string("spruce"));
+        assert_eq!(log["pillar_axis"], string("z"));
+
+        let stairs = state("minecraft:oak_stairs", 6);
+        assert_eq!(stairs["weirdo_direction"], StateValue::Int(2));
+        assert_eq!(stairs["upside_down_bit"], StateValue::Byte(1));
+
+        assert!(BlockState::from_legacy("minecraft:wool", 16).is_none());
+        assert!(BlockState::from_legacy("minecraft:not_a_block", 0).is_none());
+    }
+}
@@ -0,0 +1,62 @@
+mod legacy;
+
+use std::collections::BTreeMap;
+
+/// The value of a single block state property.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StateValue {
+    Byte(i8),
+    Int(i32),
+    String(String),
+}
+
+/// Block state properties ordered by name, so two sets of states compare
+/// equal regardless of the order in which they were read.
+pub type States = BTreeMap<String, StateValue>;
+
+/// A block described by its name and its state properties, which is how
+/// newer versions of the game store blocks in their palettes.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct BlockState {
+    pub name: String,
+    pub states: States,
+}
+
+impl BlockState {
+    /// Looks up the block state corresponding to a legacy data value of the
+    /// block with the given name. Returns `None` if the block or the data
+    /// value is not known.
+    pub fn from_legacy(name: &str, val: u16) -> Option<BlockState> {
+        let block = legacy::TABLE.by_name.get(name)?;
+        let states = block.states(val)?;
+
+        Some(BlockState {
+            name: name.to_owned(),
+            states: states.clone(),
+        })
+    }
+
+    /// Like `from_legacy`, but identifies the block by the numeric ID used
+    /// in the oldest world formats.
+    pub fn from_legacy_id(id: u8, val: u16) -> Option<BlockState> {
+        BlockState::from_legacy(legacy_name(id)?, val)
+    }
+
+    /// Looks up the legacy data value corresponding to these states. Returns
+    /// `None` if the block is not known or if the combination of states has
+    /// no legacy equivalent.
+    pub fn to_legacy(&self) -> Option<u16> {
+        legacy_val(&self.name, &self.states)
+    }
+}
+
+// Same as `BlockState::to_legacy`, but avoids building a `BlockState` when the
+// name and states are already available separately.
+pub(crate) fn legacy_val(name: &str, states: &States) -> Option<u16> {
+    let block = legacy::TABLE.by_name.get(name)?;
+    block.val(states)
+}
+
+pub(crate) fn legacy_name(id: u8) -> Option<&'static str> {
+    legacy::TABLE.by_id.get(&id).map(|name| name.as_str())
+}
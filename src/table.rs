@@ -1,14 +1,27 @@
+use failure::bail;
 use fnv::FnvHashMap;
 use std::num::NonZeroU16;
 
+use crate::error::*;
+use crate::raw::PaletteEntry;
+
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 pub struct BlockId(NonZeroU16);
 
 pub const AIR: BlockId = BlockId(unsafe { NonZeroU16::new_unchecked(1) });
 
+// Blocks whose states have no data value are given one from here upwards.
+// Data values are stored as shorts, and negative ones are rejected when they
+// are read, so data values read from a world never reach this range.
+const FIRST_STATES_VAL: u16 = 0x8000;
+
 pub struct BlockTable {
     id_to_name: Vec<String>,
     name_to_id: FnvHashMap<String, BlockId>,
+    // palette entries with block states that have no data value, which are
+    // written back as they were read
+    val_to_entry: FnvHashMap<(BlockId, u16), PaletteEntry>,
+    entry_to_val: FnvHashMap<PaletteEntry, u16>,
 }
 
 impl BlockTable {
@@ -23,9 +36,16 @@ impl BlockTable {
         BlockTable {
             id_to_name,
             name_to_id,
+            val_to_entry: FnvHashMap::default(),
+            entry_to_val: FnvHashMap::default(),
         }
     }
 
+    // returns the ID of a name without adding it
+    pub fn lookup(&self, name: &str) -> Option<BlockId> {
+        self.name_to_id.get(name).cloned()
+    }
+
     pub fn get_id(&mut self, name: &str) -> BlockId {
         if let Some(id) = self.name_to_id.get(name) {
             *id
@@ -43,4 +63,36 @@ impl BlockTable {
     pub fn get_name(&self, id: BlockId) -> &str {
         &self.id_to_name[id.0.get() as usize - 1]
     }
+
+    // returns the data value standing in for a palette entry whose states
+    // have no data value, without adding it
+    pub fn lookup_states(&self, entry: &PaletteEntry) -> Option<u16> {
+        self.entry_to_val.get(entry).cloned()
+    }
+
+    // The data value standing in for a palette entry whose states have no
+    // data value. The entry should not hold extra fields, those are kept
+    // with the subchunk.
+    pub fn get_states_val(&mut self, id: BlockId, entry: &PaletteEntry) -> Result<u16> {
+        if let Some(val) = self.entry_to_val.get(entry) {
+            return Ok(*val);
+        }
+
+        let count = self.entry_to_val.len();
+        if count > usize::from(u16::MAX - FIRST_STATES_VAL) {
+            bail!("too many block states without a data value");
+        }
+
+        let val = FIRST_STATES_VAL + count as u16;
+        self.val_to_entry.insert((id, val), entry.clone());
+        self.entry_to_val.insert(entry.clone(), val);
+
+        Ok(val)
+    }
+
+    // the palette entry a data value stands in for, if it is not a real
+    // data value
+    pub fn states_entry(&self, id: BlockId, val: u16) -> Option<&PaletteEntry> {
+        self.val_to_entry.get(&(id, val))
+    }
 }
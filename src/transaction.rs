@@ -0,0 +1,95 @@
+use failure::bail;
+use fnv::FnvHashMap;
+
+use crate::chunk::AIR_INFO;
+use crate::error::*;
+use crate::pos::*;
+use crate::world::{BlockLayers, World};
+
+const AIR_LAYERS: BlockLayers = BlockLayers {
+    layer1: AIR_INFO,
+    layer2: AIR_INFO,
+};
+
+/// Changes to a world that are kept apart from it until they are committed,
+/// see `World::transaction`. Reads see the changes made so far.
+pub struct Transaction<'a> {
+    world: &'a World,
+    chunks: FnvHashMap<ChunkPos, ChunkChanges>,
+}
+
+#[derive(Default)]
+pub(crate) struct ChunkChanges {
+    // whether the whole chunk was added or deleted before its blocks were
+    // changed
+    pub replaced: Option<Replacement>,
+    pub blocks: FnvHashMap<LocalPos, BlockLayers>,
+}
+
+#[derive(Copy, Clone)]
+pub(crate) enum Replacement {
+    Added,
+    Deleted,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(world: &'a World) -> Transaction<'a> {
+        Transaction {
+            world,
+            chunks: FnvHashMap::default(),
+        }
+    }
+
+    pub fn get_block(&self, pos: &WorldPos) -> Result<Option<BlockLayers>> {
+        let changes = match self.chunks.get(&pos.chunk_pos()) {
+            Some(changes) => changes,
+            None => return self.world.get_block(pos),
+        };
+
+        if let Some(data) = changes.blocks.get(&pos.local_pos()) {
+            return Ok(Some(*data));
+        }
+
+        match changes.replaced {
+            Some(Replacement::Added) => Ok(Some(AIR_LAYERS)),
+            Some(Replacement::Deleted) => Ok(None),
+            None => self.world.get_block(pos),
+        }
+    }
+
+    /// Sets a block in the transaction. Like `World::set_block`, this fails
+    /// if there is no chunk at the position, counting the chunks added and
+    /// deleted in the transaction.
+    pub fn set_block(&mut self, pos: &WorldPos, data: BlockLayers) -> Result<()> {
+        if self.get_block(pos)?.is_none() {
+            bail!("no chunk at {:?} to set the block in", pos.chunk_pos());
+        }
+
+        let changes = self.chunks.entry(pos.chunk_pos()).or_default();
+        changes.blocks.insert(pos.local_pos(), data);
+
+        Ok(())
+    }
+
+    pub fn add_chunk(&mut self, pos: ChunkPos) {
+        self.replace_chunk(pos, Replacement::Added);
+    }
+
+    pub fn delete_chunk(&mut self, pos: ChunkPos) {
+        self.replace_chunk(pos, Replacement::Deleted);
+    }
+
+    fn replace_chunk(&mut self, pos: ChunkPos, replacement: Replacement) {
+        let changes = ChunkChanges {
+            replaced: Some(replacement),
+            blocks: FnvHashMap::default(),
+        };
+        self.chunks.insert(pos, changes);
+    }
+
+    // Makes the changes to the world, either all of them or none, see
+    // `World::commit_transaction`.
+    pub(crate) fn commit(self) -> Result<()> {
+        self.world.commit_transaction(self.chunks)
+    }
+}
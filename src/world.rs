@@ -1,20 +1,42 @@
-use fnv::FnvHashMap;
-use fnv::FnvHashSet;
+use failure::bail;
+use fnv::{FnvHashMap, FnvHasher};
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use rayon::prelude::*;
+use std::hash::{Hash, Hasher};
 use std::cell::RefCell;
-use std::collections::hash_map::Entry;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
+use crate::cache::LruCache;
+use crate::chunk::*;
 use crate::error::*;
+use crate::history::{BlockChange, History};
+use crate::layer::Layer;
+use crate::error::{DecodeError, DecodeErrorKind};
+use crate::observer::WorldObserver;
 use crate::pos::*;
-use crate::raw::{RawWorld, BlockStorage, PaletteEntry, Subchunk, SubchunkPos};
-use crate::table::{BlockId, BlockTable, AIR};
-
-const AIR_INFO: BlockData = BlockData {
-    block_id: AIR,
-    block_val: 0,
+use crate::read_only::ReadOnlyWorld;
+use crate::transaction::{ChunkChanges, Replacement, Transaction};
+use crate::raw::{
+    self, BlockProperties, BlockStorage, PaletteEntry, RawWorld, SalvageOptions, ScanReport,
+    Subchunk, SubchunkPos, TargetVersion, WriteBatch,
 };
-const NUM_SUBCHUNKS: u8 = 16;
-const CHUNK_SIZE: usize = 4096;
+use crate::state::{self, BlockState};
+use crate::table::{BlockId, BlockTable};
+
+// number of caches the chunks are spread over, so that threads working on
+// different chunks rarely wait for each other
+const NUM_SHARDS: usize = 16;
+
+thread_local! {
+    // ids of the worlds whose `with_chunk` is running on this thread
+    static CHUNK_USERS: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+// ids of the worlds created so far, which tell the chunks of one world from
+// those of another
+static NEXT_WORLD_ID: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 pub struct BlockData {
@@ -28,61 +50,176 @@ pub struct BlockLayers {
     pub layer2: BlockData,
 }
 
-type ChunkCache = FnvHashMap<ChunkPos, Option<Chunk>>;
-
-pub struct World {
-    raw_world: RawWorld,
-    global_palette: RefCell<BlockTable>,
-    chunk_cache: RefCell<ChunkCache>,
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SaveOptions {
+    /// Wait until the changes have reached the disk, so that they survive a
+    /// crash of the system and not just of the program.
+    pub sync: bool,
 }
 
-// uses indices into table stored in the World instead of a separate palette for
-// each subchunk
-#[derive(Debug, Clone)]
-struct WorldSubchunk {
-    data1: Vec<BlockData>,
-    data2: Vec<BlockData>,
-}
+// A chunk is locked on its own while it is used, the cache holding it is only
+// locked to look it up. Whenever both are locked, the cache is locked first.
+type ChunkRef = Arc<Mutex<CachedChunk>>;
+type ChunkCache = LruCache<ChunkPos, ChunkRef>;
+
+// a chunk that changed, with whether it was added or deleted as a whole and
+// the blocks in it that changed
+type ChangedChunk = (ChunkPos, Option<Replacement>, Vec<BlockChange>);
 
 #[derive(Debug, Clone)]
-struct Chunk {
-    // this vector should always hold 16 subchunks
-    subchunks: Vec<WorldSubchunk>,
+enum CachedChunk {
+    // the chunk is not stored in the world
+    Absent,
+    // the chunk is stored in the world, but is removed on the next save
+    Deleted,
+    Present(Chunk),
 }
 
-impl Chunk {
-    fn get_block(&self, w: &WorldPos) -> BlockLayers {
-        let sub_y = w.subchunk_y();
-        let sub_offset = w.subchunk_offset();
-
-        let subchunk = &self.subchunks[sub_y];
-        let block1 = subchunk.data1[sub_offset];
-        let block2 = subchunk.data2[sub_offset];
-        BlockLayers {
-            layer1: block1,
-            layer2: block2,
+impl CachedChunk {
+    // whether the chunk has changes that have not been written yet
+    fn is_dirty(&self) -> bool {
+        match self {
+            CachedChunk::Absent => false,
+            CachedChunk::Deleted => true,
+            CachedChunk::Present(c) => c.is_dirty(),
         }
     }
 
-    fn set_block(&mut self, w: &WorldPos, d: BlockLayers) {
-        let sub_y = w.subchunk_y();
-        let sub_offset = w.subchunk_offset();
-
-        let subchunk = &mut self.subchunks[sub_y];
-
-        subchunk.data1[sub_offset] = d.layer1;
-        subchunk.data2[sub_offset] = d.layer2;
+    // called once the changes to the chunk have been written
+    fn mark_saved(&mut self) {
+        match self {
+            CachedChunk::Absent => {}
+            CachedChunk::Deleted => *self = CachedChunk::Absent,
+            CachedChunk::Present(c) => {
+                for sc in c.subchunks.iter_mut().flatten() {
+                    sc.dirty = false;
+                }
+            }
+        }
     }
 }
 
+/// A world that can be shared between threads. Chunks are locked
+/// separately, so threads working on different chunks do not block each
+/// other.
+//
+// Locks are taken in one order, so threads never wait for each other in a
+// circle: first the caches in the order of the shards, then the chunks in
+// the order of their positions. Nothing waits for a cache while holding a
+// chunk, which is why `with_chunk` does not allow its function to use the
+// world.
+pub struct World {
+    // chunks handed out carry this id, as their block ids refer to the
+    // table of this world
+    id: u64,
+    raw_world: RawWorld,
+    global_palette: RwLock<BlockTable>,
+    // chunks are spread over the shards by their position
+    chunk_cache: Vec<Mutex<ChunkCache>>,
+    // maximum number of chunks in the caches together, if it is limited
+    cache_capacity: Option<usize>,
+    // format to save subchunks in. if it is not known for the whole world,
+    // each chunk is saved in the format matching its chunk version
+    target: Option<TargetVersion>,
+    history: Mutex<History>,
+    history_enabled: bool,
+    observers: Vec<Arc<dyn WorldObserver>>,
+}
+
 impl World {
     pub fn open(path: &Path) -> Result<World> {
         let raw_world = RawWorld::open(path)?;
-        Ok(World {
+        let target = detect_target_version(path);
+
+        Ok(World::with_raw_world(raw_world, target))
+    }
+
+    /// Opens a world that can only be read, see `RawWorld::open_read_only`.
+    pub fn open_read_only(path: &Path) -> Result<ReadOnlyWorld> {
+        let raw_world = RawWorld::open_read_only(path)?;
+        let target = detect_target_version(path);
+
+        Ok(ReadOnlyWorld::new(World::with_raw_world(raw_world, target)))
+    }
+
+    /// Opens a copy of a world that can only be read, which also works
+    /// while the game has the world open. See
+    /// `RawWorld::open_read_only_copy`.
+    pub fn open_read_only_copy(path: &Path) -> Result<ReadOnlyWorld> {
+        let raw_world = RawWorld::open_read_only_copy(path)?;
+        let target = detect_target_version(path);
+
+        Ok(ReadOnlyWorld::new(World::with_raw_world(raw_world, target)))
+    }
+
+    /// An empty world kept in memory, whose subchunks are saved in the
+    /// given format. See `RawWorld::copy_to` to store it on disk.
+    pub fn in_memory(target: TargetVersion) -> World {
+        World::with_raw_world(RawWorld::in_memory(), Some(target))
+    }
+
+    /// A world on top of the given raw world, which may use any storage.
+    /// Without a target version, each chunk is saved in the format matching
+    /// its chunk version.
+    pub fn with_raw_world(mut raw_world: RawWorld, target: Option<TargetVersion>) -> World {
+        // subchunks are saved as a whole, so anything the world does not
+        // understand has to be kept to avoid losing it on save
+        raw_world.set_lenient(true);
+
+        World {
+            id: NEXT_WORLD_ID.fetch_add(1, Ordering::Relaxed),
             raw_world,
-            global_palette: RefCell::new(BlockTable::new()),
-            chunk_cache: RefCell::new(FnvHashMap::default()),
-        })
+            global_palette: RwLock::new(BlockTable::new()),
+            chunk_cache: (0..NUM_SHARDS)
+                .map(|_| Mutex::new(LruCache::new()))
+                .collect(),
+            cache_capacity: None,
+            target,
+            history: Mutex::new(History::new(0)),
+            history_enabled: false,
+            observers: Vec::new(),
+        }
+    }
+
+    /// The raw world below this one. Changes made through it are not seen
+    /// by chunks that are already loaded.
+    pub fn raw_world(&self) -> &RawWorld {
+        &self.raw_world
+    }
+
+    /// The format subchunks are saved in, if it is known for the whole world.
+    pub fn target_version(&self) -> Option<TargetVersion> {
+        self.target
+    }
+
+    /// Saves all subchunks in the given format instead of the detected one,
+    /// for example to keep the world readable by an older version of the
+    /// game.
+    pub fn set_target_version(&mut self, target: TargetVersion) {
+        self.target = Some(target);
+    }
+
+    /// Limits the number of chunks kept in memory. When the limit is
+    /// reached, the least recently used chunk without unsaved changes is
+    /// dropped from memory. A chunk takes up to about 256 KiB, and far less
+    /// if its layers hold few kinds of blocks. `None` removes the limit.
+    ///
+    /// Changed chunks are only written by `save`, which writes all of them
+    /// at once, so they stay in memory until then even if that exceeds the
+    /// limit. Code changing many chunks can save in between to bound the
+    /// memory it uses.
+    ///
+    /// The chunks are spread over 16 caches that are locked separately, and
+    /// each of them gets an equal share of the capacity, rounded up, with a
+    /// minimum of one chunk. So up to 15 chunks more than the limit can be
+    /// kept in memory, and at least 16. Chunks in use by a thread are not
+    /// dropped either.
+    pub fn set_cache_capacity(&mut self, capacity: Option<usize>) {
+        self.cache_capacity = capacity;
+
+        for shard in &self.chunk_cache {
+            self.evict_chunks(&mut shard.lock());
+        }
     }
 
     pub fn iter_chunks<'a>(&'a self) -> impl Iterator<Item = Result<ChunkPos>> + 'a {
@@ -103,30 +240,80 @@ impl World {
         })
     }
 
-    fn translate_block_storage(&self, storage: &BlockStorage) -> Vec<BlockData> {
-        storage
+    // Translates the palette of a storage once, the indices of the blocks
+    // are kept as they are.
+    fn translate_block_storage(
+        &self,
+        storage: &BlockStorage,
+        extra_fields: &mut FnvHashMap<BlockData, Vec<u8>>,
+    ) -> Result<Layer> {
+        let palette_len = storage.palette.len();
+        if let Some(index) = storage
             .blocks
             .iter()
-            .map(|b| {
-                let description = &storage.palette[*b as usize];
-                let block_id = self.global_palette.borrow_mut().get_id(&description.name);
-                BlockData {
-                    block_id,
-                    block_val: description.val,
-                }
-            })
-            .collect()
+            .find(|b| usize::from(**b) >= palette_len)
+        {
+            let kind = DecodeErrorKind::BadPaletteIndex {
+                index: *index,
+                palette_len,
+            };
+            return Err(DecodeError::new(kind).into());
+        }
+
+        // the table is shared between threads, so it is only locked for
+        // writing if the palette holds blocks that are new to it
+        let known_blocks = {
+            let global_palette = self.global_palette.read();
+            storage
+                .palette
+                .iter()
+                .map(|description| lookup_block(&global_palette, description))
+                .collect::<Option<Vec<_>>>()
+        };
+        let blocks = match known_blocks {
+            Some(blocks) => blocks,
+            None => {
+                let mut global_palette = self.global_palette.write();
+                storage
+                    .palette
+                    .iter()
+                    .map(|description| add_block(&mut global_palette, description))
+                    .collect::<Result<Vec<_>>>()?
+            }
+        };
+
+        for (description, block) in storage.palette.iter().zip(&blocks) {
+            if !description.extra_fields.is_empty() {
+                extra_fields.insert(*block, description.extra_fields.clone());
+            }
+        }
+
+        Ok(Layer::from_palette(blocks, &storage.blocks))
     }
 
     fn load_subchunk(&self, pos: &SubchunkPos) -> Result<Option<WorldSubchunk>> {
         let maybe_sc = self.raw_world.load_subchunk(pos)?;
 
-        Ok(maybe_sc.as_ref().map(|sc| self.convert_subchunk(sc)))
+        match maybe_sc {
+            Some(sc) => {
+                let converted = self
+                    .convert_subchunk(&sc)
+                    .map_err(|e| DecodeError::in_subchunk(e, *pos))?;
+                Ok(Some(converted))
+            }
+            None => Ok(None),
+        }
     }
 
-    fn save_subchunk(&self, pos: &SubchunkPos, sc: &WorldSubchunk) -> Result<()> {
-        let converted = self.convert_world_subchunk(sc);
-        self.raw_world.save_subchunk(pos, &converted)?;
+    fn save_subchunk(
+        &self,
+        batch: &mut WriteBatch,
+        pos: &SubchunkPos,
+        sc: &WorldSubchunk,
+        target: TargetVersion,
+    ) -> Result<()> {
+        let converted = self.convert_world_subchunk(sc, target)?;
+        batch.save_subchunk(pos, &converted, target)?;
 
         Ok(())
     }
@@ -139,205 +326,1828 @@ impl World {
         }
     }
 
-    fn convert_subchunk(&self, sc: &Subchunk) -> WorldSubchunk {
+    fn convert_subchunk(&self, sc: &Subchunk) -> Result<WorldSubchunk> {
+        // should have at least one BlockStorage, storages after the second
+        // are kept as they are. if all storages are in an unknown format,
+        // the subchunk is treated as air
         let count = sc.block_storages.len();
-        assert!(
-            count == 1 || count == 2,
-            "should have one or two BlockStorages"
-        );
+        if count == 0 && sc.opaque_storages.is_none() {
+            return Err(DecodeError::new(DecodeErrorKind::BadStorageCount(count)).into());
+        }
 
-        let bs1 = self.translate_block_storage(&sc.block_storages[0]);
+        let mut preserved = PreservedData {
+            storages_read: count,
+            extra_layers: sc.block_storages.iter().skip(2).cloned().collect(),
+            opaque_storages: sc.opaque_storages.clone(),
+            extra_fields: FnvHashMap::default(),
+        };
+
+        let bs1 = match sc.block_storages.first() {
+            Some(bs) => self.translate_block_storage(bs, &mut preserved.extra_fields)?,
+            None => create_air_layer(),
+        };
 
         // second blockstorage might be missing
-        let bs2 = sc
-            .block_storages
-            .get(1)
-            .map(|bs| self.translate_block_storage(&bs))
-            .unwrap_or_else(create_air_layer);
+        let bs2 = match sc.block_storages.get(1) {
+            Some(bs) => self.translate_block_storage(bs, &mut preserved.extra_fields)?,
+            None => create_air_layer(),
+        };
 
-        WorldSubchunk {
+        Ok(WorldSubchunk {
             data1: bs1,
             data2: bs2,
-        }
+            preserved,
+            dirty: false,
+        })
     }
 
-    fn create_palette(
+    fn convert_world_layer(
         &self,
-        layer: &[BlockData],
-    ) -> (FnvHashMap<BlockData, u16>, Vec<PaletteEntry>) {
-        let unique_blocks: FnvHashSet<BlockData> = layer.iter().cloned().collect();
-        let unique_blocks: Vec<BlockData> = unique_blocks.iter().cloned().collect();
-
-        // mapping from BlockData to index in the palette
-        let mapping = unique_blocks
-            .iter()
-            .enumerate()
-            .map(|(i, bi)| (*bi, i as u16))
-            .collect();
+        layer: &Layer,
+        target: TargetVersion,
+        extra_fields: &FnvHashMap<BlockData, Vec<u8>>,
+    ) -> Result<BlockStorage> {
+        // blocks are added to the palette in the order they first appear in,
+        // with air first like the game does, so the same blocks always give
+        // the same palette
+        let (unique_blocks, blocks) = layer.to_palette(AIR_INFO);
 
         // create a palette by looking up the names corresponding to
         // the block IDs
+        let global_palette = self.global_palette.read();
         let palette = unique_blocks
             .iter()
-            .map(|bi| PaletteEntry {
-                name: self.block_name(bi.block_id),
-                val: bi.block_val,
+            .map(|bi| {
+                let name = global_palette.get_name(bi.block_id);
+                let mut entry = match global_palette.states_entry(bi.block_id, bi.block_val) {
+                    // block states without a data value are written as they
+                    // were read, which needs a format with block states
+                    Some(entry) if target.block_states() => entry.clone(),
+                    Some(_) => bail!(
+                        "block {} has block states without a data value, which cannot be \
+                         written for {:?}",
+                        name,
+                        target
+                    ),
+                    None => palette_entry(name.to_owned(), bi.block_val, target),
+                };
+                if let Some(fields) = extra_fields.get(bi) {
+                    entry.extra_fields = fields.clone();
+                }
+                Ok(entry)
             })
-            .collect();
+            .collect::<Result<_>>()?;
 
-        (mapping, palette)
+        Ok(BlockStorage { blocks, palette })
     }
 
-    fn convert_world_layer(&self, layer: &[BlockData]) -> BlockStorage {
-        let (mapping, palette) = self.create_palette(layer);
-        let paletted_blocks = layer.iter().map(|bi| mapping[bi]).collect();
+    fn convert_world_subchunk(
+        &self,
+        sc: &WorldSubchunk,
+        target: TargetVersion,
+    ) -> Result<Subchunk> {
+        let preserved = &sc.preserved;
 
-        BlockStorage {
-            blocks: paletted_blocks,
-            palette,
+        // storages that were all in an unknown format are written back on
+        // their own, unless blocks were placed in front of them
+        let only_opaque = preserved.storages_read == 0
+            && preserved.opaque_storages.is_some()
+            && sc.data1.is_all(AIR_INFO)
+            && sc.data2.is_all(AIR_INFO);
+        if only_opaque && target != TargetVersion::SingleStorage {
+            return Ok(Subchunk {
+                version: target.subchunk_version(),
+                block_storages: Vec::new(),
+                opaque_storages: preserved.opaque_storages.clone(),
+            });
         }
-    }
 
-    fn convert_world_subchunk(&self, sc: &WorldSubchunk) -> Subchunk {
         let mut layers = Vec::new();
+        layers.push(self.convert_world_layer(&sc.data1, target, &preserved.extra_fields)?);
 
-        layers.push(self.convert_world_layer(&sc.data1));
-        layers.push(self.convert_world_layer(&sc.data2));
+        // the oldest format has room for a single storage only, so nothing
+        // else can be kept
+        if target == TargetVersion::SingleStorage {
+            return Ok(Subchunk {
+                version: target.subchunk_version(),
+                block_storages: layers,
+                opaque_storages: None,
+            });
+        }
 
-        Subchunk {
-            block_storages: layers,
+        // an empty second layer is left out like the game does, unless
+        // storages that were read after it have to stay in place
+        let keep_layer2 = !preserved.extra_layers.is_empty()
+            || (preserved.opaque_storages.is_some() && preserved.storages_read >= 2);
+        if keep_layer2 || !sc.data2.is_all(AIR_INFO) {
+            layers.push(self.convert_world_layer(&sc.data2, target, &preserved.extra_fields)?);
         }
+
+        layers.extend(preserved.extra_layers.iter().cloned());
+
+        Ok(Subchunk {
+            version: target.subchunk_version(),
+            block_storages: layers,
+            opaque_storages: preserved.opaque_storages.clone(),
+        })
     }
 
-    fn load_chunk(&self, pos: &ChunkPos) -> Result<Option<Chunk>> {
+    fn load_chunk(&self, pos: &ChunkPos) -> Result<CachedChunk> {
         // If the bottom-most subchunk is not there, then the chunk has not been
         // stored in the world. Hence the bottom-most subchunk must be present.
-        let maybe_first = self.load_subchunk(&pos.subchunk_pos(0))?;
-
-        if let Some(first) = maybe_first {
-            let mut subchunks = Vec::with_capacity(usize::from(NUM_SUBCHUNKS));
-            subchunks.push(first);
-
-            // load the other subchunks as well
-            for i in 1..NUM_SUBCHUNKS {
-                subchunks.push(self.load_subchunk_or_air(&pos.subchunk_pos(i as u8))?);
-            }
-
-            Ok(Some(Chunk { subchunks }))
+        if self.raw_world.has_subchunk(&pos.subchunk_pos(0))? {
+            // the subchunks themselves are loaded when they are used
+            let subchunks = vec![None; usize::from(NUM_SUBCHUNKS)];
+            Ok(CachedChunk::Present(Chunk {
+                world_id: self.id,
+                subchunks,
+            }))
         } else {
             // chunk is not present
-            Ok(None)
+            Ok(CachedChunk::Absent)
         }
     }
 
-    fn do_save_chunk(&self, pos: &ChunkPos, chunk: &Chunk) -> Result<()> {
-        // TODO: Optimize so subchunks filled with air at the top of
-        // the world do not get saved.
+    fn chunk_target(&self, pos: &ChunkPos) -> Result<TargetVersion> {
+        if let Some(target) = self.target {
+            return Ok(target);
+        }
+
+        let version = self.raw_world.load_chunk_version(pos)?;
+        Ok(version
+            .map(TargetVersion::from_chunk_version)
+            .unwrap_or(TargetVersion::LATEST))
+    }
+
+    fn do_save_chunk(&self, batch: &mut WriteBatch, pos: &ChunkPos, chunk: &Chunk) -> Result<()> {
+        // only subchunks that changed are written
+        if !chunk.is_dirty() {
+            return Ok(());
+        }
+
+        let target = self.chunk_target(pos)?;
+        let top = self.top_subchunk(pos, chunk)?;
+
         for i in 0..NUM_SUBCHUNKS {
-            self.save_subchunk(&pos.subchunk_pos(i), &chunk.subchunks[usize::from(i)])?;
+            // subchunks that are not loaded are unchanged, and are stored if
+            // they are below the top
+            let sc = match chunk.subchunks[usize::from(i)] {
+                Some(ref sc) => sc,
+                None => continue,
+            };
+
+            if usize::from(i) > top {
+                // remove the stale copy of a subchunk that is empty, which
+                // may have been stored while there were blocks above it
+                if self.raw_world.has_subchunk(&pos.subchunk_pos(i))? {
+                    batch.delete_subchunk(&pos.subchunk_pos(i))?;
+                }
+            } else if sc.dirty {
+                self.save_subchunk(batch, &pos.subchunk_pos(i), sc, target)?;
+            }
         }
 
         Ok(())
     }
 
-    fn do_delete_chunk(&self, pos: &ChunkPos) -> Result<()> {
+    // Subchunks filled with air above the terrain are not stored, except for
+    // the bottom one which marks the chunk as present. Returns the highest
+    // subchunk that has to be stored.
+    fn top_subchunk(&self, pos: &ChunkPos, chunk: &Chunk) -> Result<usize> {
+        for i in (1..NUM_SUBCHUNKS).rev() {
+            let in_use = match chunk.subchunks[usize::from(i)] {
+                Some(ref sc) => !sc.is_empty(),
+                // a subchunk that was not loaded is kept if it is stored
+                None => self.raw_world.has_subchunk(&pos.subchunk_pos(i))?,
+            };
+
+            if in_use {
+                return Ok(usize::from(i));
+            }
+        }
+
+        Ok(0)
+    }
+
+    // returns the subchunk at the given height, loading it if needed
+    fn loaded_subchunk<'a>(
+        &self,
+        pos: &ChunkPos,
+        chunk: &'a mut Chunk,
+        sub_y: usize,
+    ) -> Result<&'a mut WorldSubchunk> {
+        let slot = &mut chunk.subchunks[sub_y];
+        if slot.is_none() {
+            *slot = Some(self.load_subchunk_or_air(&pos.subchunk_pos(sub_y as u8))?);
+        }
+
+        Ok(slot.as_mut().unwrap())
+    }
+
+    fn do_delete_chunk(&self, batch: &mut WriteBatch, pos: &ChunkPos) -> Result<()> {
         for i in 0..NUM_SUBCHUNKS {
-            self.raw_world.delete_subchunk(&pos.subchunk_pos(i))?;
+            batch.delete_subchunk(&pos.subchunk_pos(i))?;
         }
 
         Ok(())
     }
 
-    fn cached_chunk<'a>(
+    fn save_cached_chunk(
         &self,
-        cache: &'a mut ChunkCache,
-        chunk_pos: ChunkPos,
-    ) -> Result<&'a mut Option<Chunk>> {
-        let entry = cache.entry(chunk_pos);
+        batch: &mut WriteBatch,
+        pos: &ChunkPos,
+        chunk: &CachedChunk,
+    ) -> Result<()> {
+        match chunk {
+            CachedChunk::Present(c) => self.do_save_chunk(batch, pos, c),
+            CachedChunk::Deleted => self.do_delete_chunk(batch, pos),
+            CachedChunk::Absent => Ok(()),
+        }
+    }
+
+    // writes the changes to a single chunk
+    fn flush_cached_chunk(&self, pos: &ChunkPos, chunk: &mut CachedChunk) -> Result<()> {
+        if !chunk.is_dirty() {
+            return Ok(());
+        }
+
+        let mut batch = WriteBatch::new();
+        self.save_cached_chunk(&mut batch, pos, chunk)?;
+        self.raw_world.write(batch, false)?;
+        chunk.mark_saved();
+
+        Ok(())
+    }
+
+    // the cache holding the chunk at the given position
+    fn shard(&self, pos: &ChunkPos) -> &Mutex<ChunkCache> {
+        let mut hasher = FnvHasher::default();
+        pos.hash(&mut hasher);
+        &self.chunk_cache[hasher.finish() as usize % NUM_SHARDS]
+    }
+
+    // drops the least recently used chunks until the cache is within its
+    // share of the capacity. changed chunks are kept until they are saved
+    fn evict_chunks(&self, cache: &mut ChunkCache) {
+        // rounded up by hand, as `usize::div_ceil` needs Rust 1.73
+        #[allow(clippy::manual_div_ceil)]
+        let capacity = match self.cache_capacity {
+            Some(capacity) => ((capacity + NUM_SHARDS - 1) / NUM_SHARDS).max(1),
+            None => return,
+        };
+
+        while cache.len() > capacity {
+            // chunks that are in use by a thread are never evicted. no other
+            // thread can start using a chunk while the cache is locked, so
+            // locking an unused chunk does not block
+            let unused = cache
+                .keys_by_use()
+                .find(|pos| {
+                    let chunk = cache.get(pos).unwrap();
+                    Arc::strong_count(chunk) == 1 && !chunk.lock().is_dirty()
+                })
+                .cloned();
+            match unused {
+                Some(pos) => cache.remove(&pos),
+                None => break,
+            };
+        }
+    }
+
+    // Fails if the chunk of a `with_chunk` of this world is locked on this
+    // thread, as waiting for a cache while holding a chunk goes against the
+    // order of the locks.
+    fn check_lock_order(&self) -> Result<()> {
+        if CHUNK_USERS.with(|ids| ids.borrow().contains(&self.id)) {
+            bail!("the world cannot be used by the function given to `with_chunk`");
+        }
+
+        Ok(())
+    }
+
+    // locks the cache holding the chunk at the given position
+    fn lock_shard(&self, pos: &ChunkPos) -> Result<MutexGuard<'_, ChunkCache>> {
+        self.check_lock_order()?;
+        Ok(self.shard(pos).lock())
+    }
+
+    fn cached_chunk(&self, chunk_pos: ChunkPos) -> Result<ChunkRef> {
+        let mut cache = self.lock_shard(&chunk_pos)?;
 
         // try to load chunk from cache, and otherwise load from disk and put it
         // in the cache
-        if let Entry::Vacant(v) = entry {
-            let chunk = self.load_chunk(&chunk_pos)?;
-            v.insert(chunk);
+        let chunk = match cache.get_mut(&chunk_pos) {
+            Some(chunk) => chunk.clone(),
+            None => {
+                let chunk = Arc::new(Mutex::new(self.load_chunk(&chunk_pos)?));
+                cache.insert(chunk_pos, chunk.clone());
+                chunk
+            }
+        };
+
+        // the chunk is in use from here on, so it is not evicted
+        self.evict_chunks(&mut cache);
+
+        Ok(chunk)
+    }
+
+    // puts the chunk into the cache in place of the cached one, if there is
+    // one. threads already using the cached chunk see the new one
+    fn replace_chunk(&self, pos: ChunkPos, chunk: CachedChunk) -> Result<()> {
+        let mut cache = self.lock_shard(&pos)?;
+
+        match cache.get_mut(&pos) {
+            Some(cached) => *cached.lock() = chunk,
+            None => cache.insert(pos, Arc::new(Mutex::new(chunk))),
+        }
+
+        self.evict_chunks(&mut cache);
+
+        Ok(())
+    }
+
+    // loads all subchunks of a chunk that have not been loaded yet
+    fn load_all_subchunks(&self, pos: &ChunkPos, chunk: &mut Chunk) -> Result<()> {
+        for i in 0..usize::from(NUM_SUBCHUNKS) {
+            self.loaded_subchunk(pos, chunk, i)?;
+        }
+
+        Ok(())
+    }
+
+    /// Calls `f` with the chunk at the given position, or returns `None` if
+    /// the chunk is not in the world. Changes made by `f` are saved like
+    /// those made by `set_block`.
+    ///
+    /// The chunk is locked while `f` runs, so `f` must not use this world,
+    /// as it could wait for a thread that is waiting for the chunk. Any
+    /// method of the world which uses chunks fails when it is called by `f`.
+    pub fn with_chunk<F, R>(&self, pos: ChunkPos, f: F) -> Result<Option<R>>
+    where
+        F: FnOnce(&mut Chunk) -> R,
+    {
+        let chunk = self.cached_chunk(pos)?;
+        let mut maybe_chunk = chunk.lock();
+
+        let chunk = match &mut *maybe_chunk {
+            CachedChunk::Present(chunk) => chunk,
+            CachedChunk::Absent | CachedChunk::Deleted => return Ok(None),
+        };
+        self.load_all_subchunks(&pos, chunk)?;
+
+        if !self.tracks_changes() {
+            return Ok(Some(self.call_with_chunk(chunk, f)));
+        }
+
+        // the blocks that changed are found by comparing the chunk with a
+        // copy from before
+        let before = chunk.clone();
+        let result = self.call_with_chunk(chunk, f);
+        let changes = chunk_changes(pos, &before, chunk);
+
+        drop(maybe_chunk);
+        self.record_changes("edit chunk", changes);
+
+        Ok(Some(result))
+    }
+
+    // calls `f` with the locked chunk, keeping it from using the world
+    fn call_with_chunk<F, R>(&self, chunk: &mut Chunk, f: F) -> R
+    where
+        F: FnOnce(&mut Chunk) -> R,
+    {
+        // the id is removed again even if `f` panics
+        struct ChunkUse;
+
+        impl Drop for ChunkUse {
+            fn drop(&mut self) {
+                CHUNK_USERS.with(|ids| ids.borrow_mut().pop());
+            }
+        }
+
+        CHUNK_USERS.with(|ids| ids.borrow_mut().push(self.id));
+        let _chunk_use = ChunkUse;
+
+        f(chunk)
+    }
+
+    /// Returns a copy of the chunk at the given position. Changes to the
+    /// copy only reach the world through `put_chunk`.
+    pub fn chunk(&self, pos: ChunkPos) -> Result<Option<Chunk>> {
+        self.with_chunk(pos, |chunk| chunk.clone())
+    }
+
+    /// Puts a chunk into the world at the given position, replacing the
+    /// chunk that is there, if any. The chunk must come from this world,
+    /// as other worlds number their blocks differently.
+    pub fn put_chunk(&self, pos: ChunkPos, mut chunk: Chunk) -> Result<()> {
+        if chunk.world_id != self.id {
+            bail!("chunk put at {:?} comes from another world", pos);
+        }
+
+        // the chunk may not come from this position, so all of it is written
+        for sc in chunk.subchunks.iter_mut().flatten() {
+            sc.dirty = true;
         }
 
-        Ok(cache.get_mut(&chunk_pos).unwrap())
+        self.replace_chunk(pos, CachedChunk::Present(chunk))?;
+        self.notify(|observer| observer.chunk_added(pos));
+
+        Ok(())
     }
 
     pub fn get_block(&self, pos: &WorldPos) -> Result<Option<BlockLayers>> {
-        let mut cache = self.chunk_cache.borrow_mut();
-        let maybe_chunk = self.cached_chunk(&mut cache, pos.chunk_pos())?;
+        let chunk = self.cached_chunk(pos.chunk_pos())?;
+        let mut maybe_chunk = chunk.lock();
 
-        match maybe_chunk {
-            Some(chunk) => Ok(Some(chunk.get_block(pos))),
-            None => Ok(None),
+        match &mut *maybe_chunk {
+            CachedChunk::Present(chunk) => {
+                let subchunk = self.loaded_subchunk(&pos.chunk_pos(), chunk, pos.subchunk_y())?;
+                Ok(Some(subchunk.get_block(pos.subchunk_offset())))
+            }
+            CachedChunk::Absent | CachedChunk::Deleted => Ok(None),
         }
     }
 
+    /// Sets a block of a chunk which is in the world. Chunks are not made up
+    /// for blocks outside of them, so it is an error if there is none.
     pub fn set_block(&self, pos: &WorldPos, data: BlockLayers) -> Result<()> {
-        let mut cache = self.chunk_cache.borrow_mut();
-        let maybe_chunk = self.cached_chunk(&mut cache, pos.chunk_pos())?;
+        let old = {
+            let chunk = self.cached_chunk(pos.chunk_pos())?;
+            let mut maybe_chunk = chunk.lock();
 
-        match maybe_chunk {
-            Some(chunk) => {
-                chunk.set_block(pos, data);
-                Ok(())
+            match &mut *maybe_chunk {
+                CachedChunk::Present(chunk) => {
+                    let subchunk =
+                        self.loaded_subchunk(&pos.chunk_pos(), chunk, pos.subchunk_y())?;
+                    let old = subchunk.get_block(pos.subchunk_offset());
+                    subchunk.set_block(pos.subchunk_offset(), data);
+                    old
+                }
+                CachedChunk::Absent | CachedChunk::Deleted => {
+                    bail!("no chunk at {:?} to set the block in", pos.chunk_pos())
+                }
+            }
+        };
+
+        // the chunk is no longer locked, so observers can use the world
+        if old != data {
+            let change = BlockChange {
+                pos: *pos,
+                old,
+                new: data,
+            };
+            self.record_changes("set block", vec![change]);
+        }
+
+        Ok(())
+    }
+
+    // Makes the changes of a transaction, see `apply_chunk_changes`.
+    pub(crate) fn commit_transaction(
+        &self,
+        chunks: FnvHashMap<ChunkPos, ChunkChanges>,
+    ) -> Result<()> {
+        let committed = self.apply_chunk_changes(chunks)?;
+
+        self.operation("transaction", || {
+            for (pos, replaced, changed) in committed {
+                match replaced {
+                    Some(Replacement::Added) => self.notify(|observer| observer.chunk_added(pos)),
+                    Some(Replacement::Deleted) => {
+                        self.notify(|observer| observer.chunk_deleted(pos))
+                    }
+                    None => {}
+                }
+                self.record_changes("transaction", changed);
+            }
+        });
+
+        Ok(())
+    }
+
+    // Makes changes to several chunks. Everything that can fail is done
+    // before the first change is made, so on an error the world is left as
+    // it was. The chunks are locked together while they change, so other
+    // threads see either none or all of the changes. Returns the chunks
+    // that were added or deleted and the blocks that changed, which are
+    // neither recorded nor reported yet.
+    fn apply_chunk_changes(
+        &self,
+        chunks: FnvHashMap<ChunkPos, ChunkChanges>,
+    ) -> Result<Vec<ChangedChunk>> {
+        // chunks are locked in the order of their positions, see `World`
+        let mut chunks: Vec<_> = chunks.into_iter().collect();
+        chunks.sort_by_key(|(pos, _)| *pos);
+
+        // chunks in use are not evicted, so these stay in the cache
+        let chunk_refs = chunks
+            .iter()
+            .map(|(pos, _)| self.cached_chunk(*pos))
+            .collect::<Result<Vec<_>>>()?;
+        let mut locked: Vec<_> = chunk_refs.iter().map(|chunk| chunk.lock()).collect();
+
+        for ((pos, changes), maybe_chunk) in chunks.iter().zip(&mut locked) {
+            if changes.replaced.is_some() {
+                continue;
+            }
+
+            let chunk = match &mut **maybe_chunk {
+                CachedChunk::Present(chunk) => chunk,
+                CachedChunk::Absent | CachedChunk::Deleted => {
+                    bail!("chunk {:?} is not in the world", pos)
+                }
+            };
+            for local in changes.blocks.keys() {
+                self.loaded_subchunk(pos, chunk, local.subchunk_y())?;
+            }
+        }
+
+        // nothing can fail from here on
+        let mut committed = Vec::new();
+        for ((pos, changes), maybe_chunk) in chunks.into_iter().zip(&mut locked) {
+            let replaced = match changes.replaced {
+                Some(Replacement::Added) => {
+                    **maybe_chunk = CachedChunk::Present(create_air_chunk(self.id));
+                    Some(Replacement::Added)
+                }
+                Some(Replacement::Deleted) => match **maybe_chunk {
+                    CachedChunk::Present(_) => {
+                        **maybe_chunk = CachedChunk::Deleted;
+                        Some(Replacement::Deleted)
+                    }
+                    // there is nothing to delete
+                    CachedChunk::Absent | CachedChunk::Deleted => None,
+                },
+                None => None,
+            };
+
+            let changed = match &mut **maybe_chunk {
+                CachedChunk::Present(chunk) => set_loaded_blocks(pos, chunk, changes.blocks),
+                CachedChunk::Absent | CachedChunk::Deleted => Vec::new(),
+            };
+            committed.push((pos, replaced, changed));
+        }
+
+        Ok(committed)
+    }
+
+    /// Calls `f` with a transaction, whose changes are only made to the
+    /// world if `f` succeeds. If `f` returns an error, or the changes cannot
+    /// be made, for example because a chunk they are in was deleted in the
+    /// meantime, the changes are thrown away and the world is left as it
+    /// was.
+    pub fn transaction<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Transaction) -> Result<R>,
+    {
+        let mut transaction = Transaction::new(self);
+        let result = f(&mut transaction)?;
+        transaction.commit()?;
+
+        Ok(result)
+    }
+
+    /// Starts recording the changes made to blocks, so they can be undone.
+    /// Once more than `max_changes` block changes are recorded, the oldest
+    /// operations are forgotten. An operation with more changes than that
+    /// is not recorded, and leaves the older ones in the history. Adding,
+    /// deleting and putting whole chunks is not recorded.
+    pub fn enable_history(&mut self, max_changes: usize) {
+        self.history = Mutex::new(History::new(max_changes));
+        self.history_enabled = true;
+    }
+
+    /// Stops recording changes and forgets the recorded ones.
+    pub fn disable_history(&mut self) {
+        self.history = Mutex::new(History::new(0));
+        self.history_enabled = false;
+    }
+
+    /// Calls `f` and records all block changes made while it runs, from any
+    /// thread, as a single operation with the given name. Changes made
+    /// outside of an operation are recorded as an operation for each call.
+    pub fn operation<F, R>(&self, name: &str, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        if !self.history_enabled {
+            return f();
+        }
+
+        self.history.lock().begin(name);
+        let _end = OperationEnd(&self.history);
+
+        f()
+    }
+
+    /// Calls the observer whenever something changes in the world.
+    ///
+    /// To find the blocks that `with_chunk` changes, the chunk is copied
+    /// before its function is called while an observer is registered or
+    /// the history is enabled. That takes up to a few hundred kilobytes per
+    /// chunk, so `get_block` and `set_block` are cheaper for small edits.
+    pub fn add_observer(&mut self, observer: Arc<dyn WorldObserver>) {
+        self.observers.push(observer);
+    }
+
+    fn notify<F: Fn(&dyn WorldObserver)>(&self, f: F) {
+        for observer in &self.observers {
+            f(&**observer);
+        }
+    }
+
+    // whether the blocks that change have to be known
+    fn tracks_changes(&self) -> bool {
+        self.history_enabled || !self.observers.is_empty()
+    }
+
+    // records changes in the history and reports them to the observers
+    pub(crate) fn record_changes(&self, name: &str, changes: Vec<BlockChange>) {
+        if changes.is_empty() {
+            return;
+        }
+
+        self.notify_changes(&changes);
+
+        if self.history_enabled {
+            let mut history = self.history.lock();
+            history.begin(name);
+            for change in changes {
+                history.record(change);
+            }
+            history.end();
+        }
+    }
+
+    fn notify_changes(&self, changes: &[BlockChange]) {
+        self.notify(|observer| {
+            for change in changes {
+                observer.block_changed(&change.pos, change.old, change.new);
             }
-            None => panic!("out of bounds"),
+        });
+    }
+
+    /// Reverts the last recorded operation that was not undone yet, and
+    /// returns its name. The blocks are changed all at once, or not at all
+    /// if the operation cannot be reverted, for example because one of its
+    /// chunks was deleted. It then stays the next one to undo.
+    ///
+    /// The history is locked while the blocks are changed, so undoing and
+    /// redoing wait for each other and for operations being recorded.
+    /// Blocks changed by other threads at the same time are not protected,
+    /// undoing while other threads edit the same blocks is not supported.
+    pub fn undo(&self) -> Result<Option<String>> {
+        let mut history = self.history.lock();
+        if history.is_recording() {
+            bail!("cannot undo while an operation is being recorded");
+        }
+
+        let op = match history.pop_done() {
+            Some(op) => op,
+            None => return Ok(None),
+        };
+
+        let reverted = op.changes.iter().rev().map(|c| (c.pos, c.old));
+        let changes = match self.apply_changes(reverted) {
+            Ok(changes) => changes,
+            Err(e) => {
+                history.push_done(op);
+                return Err(e);
+            }
+        };
+
+        let name = op.name.clone();
+        history.push_undone(op);
+
+        // observers may change the world, which records the changes
+        drop(history);
+        self.notify_changes(&changes);
+
+        Ok(Some(name))
+    }
+
+    /// Makes the last undone operation again, and returns its name. Like
+    /// `undo`, the blocks are changed all at once or not at all.
+    pub fn redo(&self) -> Result<Option<String>> {
+        let mut history = self.history.lock();
+        if history.is_recording() {
+            bail!("cannot redo while an operation is being recorded");
+        }
+
+        let op = match history.pop_undone() {
+            Some(op) => op,
+            None => return Ok(None),
+        };
+
+        let redone = op.changes.iter().map(|c| (c.pos, c.new));
+        let changes = match self.apply_changes(redone) {
+            Ok(changes) => changes,
+            Err(e) => {
+                history.push_undone(op);
+                return Err(e);
+            }
+        };
+
+        let name = op.name.clone();
+        history.push_done(op);
+
+        drop(history);
+        self.notify_changes(&changes);
+
+        Ok(Some(name))
+    }
+
+    // sets the blocks in order without recording or reporting them, and
+    // returns the blocks that changed. a block set more than once ends up
+    // as it was set last
+    fn apply_changes<I>(&self, changes: I) -> Result<Vec<BlockChange>>
+    where
+        I: IntoIterator<Item = (WorldPos, BlockLayers)>,
+    {
+        let mut chunks: FnvHashMap<ChunkPos, ChunkChanges> = FnvHashMap::default();
+        for (pos, data) in changes {
+            let changes = chunks.entry(pos.chunk_pos()).or_default();
+            changes.blocks.insert(pos.local_pos(), data);
         }
+
+        let applied = self.apply_chunk_changes(chunks)?;
+
+        Ok(applied
+            .into_iter()
+            .flat_map(|(_, _, changed)| changed)
+            .collect())
     }
 
+    /// Deletes the chunk at the given position, it is removed from the
+    /// storage on the next save. Observers are only told about it if there
+    /// was a chunk to delete.
     pub fn delete_chunk(&self, pos: ChunkPos) -> Result<()> {
-        let mut cache = self.chunk_cache.borrow_mut();
-        cache.insert(pos, None);
+        let chunk = self.cached_chunk(pos)?;
+        let deleted = {
+            let mut chunk = chunk.lock();
+            match *chunk {
+                CachedChunk::Present(_) => {
+                    *chunk = CachedChunk::Deleted;
+                    true
+                }
+                CachedChunk::Absent | CachedChunk::Deleted => false,
+            }
+        };
+
+        if deleted {
+            self.notify(|observer| observer.chunk_deleted(pos));
+        }
+
         Ok(())
     }
 
     pub fn add_chunk(&self, pos: ChunkPos) -> Result<()> {
-        let mut cache = self.chunk_cache.borrow_mut();
-        cache.insert(pos, Some(create_air_chunk()));
+        self.replace_chunk(pos, CachedChunk::Present(create_air_chunk(self.id)))?;
+        self.notify(|observer| observer.chunk_added(pos));
+
+        Ok(())
+    }
+
+    /// Writes the changes to a chunk to the world, keeping it in memory.
+    pub fn flush_chunk(&self, pos: ChunkPos) -> Result<()> {
+        let maybe_chunk = self.lock_shard(&pos)?.peek_mut(&pos).cloned();
+
+        match maybe_chunk {
+            Some(chunk) => self.flush_cached_chunk(&pos, &mut chunk.lock()),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the changes to a chunk to the world and drops it from memory.
+    /// A chunk that another thread is using stays in memory.
+    pub fn unload_chunk(&self, pos: ChunkPos) -> Result<()> {
+        let mut cache = self.lock_shard(&pos)?;
+
+        let chunk = match cache.peek_mut(&pos) {
+            Some(chunk) => chunk.clone(),
+            None => return Ok(()),
+        };
+        self.flush_cached_chunk(&pos, &mut chunk.lock())?;
+
+        // the cache holds one reference and this function the other
+        if Arc::strong_count(&chunk) == 2 {
+            cache.remove(&pos);
+        }
+
         Ok(())
     }
 
+    /// Calls `f` for every chunk stored in the world, on the threads of the
+    /// current rayon thread pool. Nothing is loaded up front, `f` reads the
+    /// chunk through the world so only the subchunks it uses are decoded, on
+    /// the thread running it. A different pool can be used by calling this
+    /// function from `ThreadPool::install`. Stops at the first error.
+    pub fn par_for_each_chunk<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn(&World, ChunkPos) -> Result<()> + Sync,
+    {
+        let positions = self.iter_chunks().collect::<Result<Vec<_>>>()?;
+
+        positions.into_par_iter().try_for_each(|pos| f(self, pos))
+    }
+
     pub fn save(&self) -> Result<()> {
-        let cache = self.chunk_cache.borrow();
+        self.save_with_options(&SaveOptions::default())
+    }
 
-        for (pos, chunk) in cache.iter() {
-            if let Some(c) = chunk {
-                self.do_save_chunk(pos, c)?;
-            } else {
-                self.do_delete_chunk(pos)?;
-            }
+    /// Saves all changes at once, so the world on disk holds either all of
+    /// them or none of them.
+    pub fn save_with_options(&self, options: &SaveOptions) -> Result<()> {
+        // the caches and then the chunks are locked in the order of the
+        // locks, see `World`. the chunks stay locked until they are marked
+        // as saved
+        self.check_lock_order()?;
+        let caches: Vec<_> = self.chunk_cache.iter().map(|shard| shard.lock()).collect();
+        let mut cached: Vec<(ChunkPos, &ChunkRef)> = caches
+            .iter()
+            .flat_map(|cache| cache.iter())
+            .map(|(pos, chunk)| (*pos, chunk))
+            .collect();
+        cached.sort_by_key(|(pos, _)| *pos);
+        let mut chunks: Vec<(ChunkPos, MutexGuard<CachedChunk>)> = cached
+            .into_iter()
+            .map(|(pos, chunk)| (pos, chunk.lock()))
+            .collect();
+
+        let mut batch = WriteBatch::new();
+        for (pos, chunk) in &chunks {
+            self.save_cached_chunk(&mut batch, pos, chunk)?;
         }
 
+        self.raw_world.write(batch, options.sync)?;
+
+        // the cache only matches the world once the batch has been written
+        for (_, chunk) in &mut chunks {
+            chunk.mark_saved();
+        }
+
+        drop(chunks);
+        drop(caches);
+        self.notify(|observer| observer.saved());
+
         Ok(())
     }
 
+    /// Checks every chunk record in the world, see `RawWorld::scan`.
+    pub fn scan(&self) -> Result<ScanReport> {
+        self.raw_world.scan()
+    }
+
+    /// Repairs the broken records in the world, see `RawWorld::salvage`.
+    /// Cached chunks are dropped so they are loaded again from the repaired
+    /// world, which discards changes that have not been saved.
+    pub fn salvage(&mut self, options: &SalvageOptions) -> Result<ScanReport> {
+        let report = self.raw_world.salvage(options)?;
+        for shard in &mut self.chunk_cache {
+            shard.get_mut().clear();
+        }
+
+        Ok(report)
+    }
+
     pub fn block_id(&self, name: &str) -> BlockId {
-        self.global_palette.borrow_mut().get_id(name)
+        if let Some(id) = self.global_palette.read().lookup(name) {
+            return id;
+        }
+
+        self.global_palette.write().get_id(name)
     }
 
     pub fn block_name(&self, id: BlockId) -> String {
-        self.global_palette.borrow_mut().get_name(id).to_owned()
+        self.global_palette.read().get_name(id).to_owned()
     }
 }
 
-fn create_air_layer() -> Vec<BlockData> {
-    vec![AIR_INFO; CHUNK_SIZE]
+// ends the operation being recorded, also if its function panics
+struct OperationEnd<'a>(&'a Mutex<History>);
+
+impl<'a> Drop for OperationEnd<'a> {
+    fn drop(&mut self) {
+        self.0.lock().end();
+    }
 }
 
-fn create_air_subchunk() -> WorldSubchunk {
-    let blocks = create_air_layer();
+// sets blocks in a chunk whose subchunks holding them are loaded, and
+// returns the blocks that changed
+fn set_loaded_blocks<I>(pos: ChunkPos, chunk: &mut Chunk, blocks: I) -> Vec<BlockChange>
+where
+    I: IntoIterator<Item = (LocalPos, BlockLayers)>,
+{
+    let mut changes = Vec::new();
+    for (local, data) in blocks {
+        let subchunk = chunk.subchunk_mut(local.subchunk_y());
+        let old = subchunk.get_block(local.subchunk_offset());
+        subchunk.set_block(local.subchunk_offset(), data);
 
-    WorldSubchunk {
-        data1: blocks.clone(),
-        data2: blocks.clone(),
+        if old != data {
+            changes.push(BlockChange {
+                pos: pos.world_pos(local),
+                old,
+                new: data,
+            });
+        }
     }
+
+    changes
 }
 
-fn create_air_chunk() -> Chunk {
-    let sc = create_air_subchunk();
-    let subchunks = vec![sc.clone(); usize::from(NUM_SUBCHUNKS)];
-    Chunk { subchunks }
+// the blocks that differ between two versions of a chunk
+fn chunk_changes(pos: ChunkPos, before: &Chunk, after: &Chunk) -> Vec<BlockChange> {
+    before
+        .iter()
+        .zip(after.iter())
+        .filter(|((_, old), (_, new))| old != new)
+        .map(|((local, old), (_, new))| BlockChange {
+            pos: pos.world_pos(local),
+            old,
+            new,
+        })
+        .collect()
+}
+
+// The block a palette entry stands for, or `None` if the table does not
+// know it yet. The world works with data values, so block states are
+// translated back to their data value.
+fn lookup_block(table: &BlockTable, entry: &PaletteEntry) -> Option<BlockData> {
+    let block_id = table.lookup(&entry.name)?;
+    let block_val = match entry.properties {
+        BlockProperties::Val(val) => val,
+        BlockProperties::States { ref states, .. } => {
+            match state::legacy_val(&entry.name, states) {
+                Some(val) => val,
+                None => table.lookup_states(&without_extra_fields(entry))?,
+            }
+        }
+    };
+
+    Some(BlockData {
+        block_id,
+        block_val,
+    })
+}
+
+// Like `lookup_block`, but adds the block to the table if it is new. States
+// without a data value are kept in the table, so they can be written back
+// unchanged.
+fn add_block(table: &mut BlockTable, entry: &PaletteEntry) -> Result<BlockData> {
+    let block_id = table.get_id(&entry.name);
+    let block_val = match entry.properties {
+        BlockProperties::Val(val) => val,
+        BlockProperties::States { ref states, .. } => {
+            match state::legacy_val(&entry.name, states) {
+                Some(val) => val,
+                None => table.get_states_val(block_id, &without_extra_fields(entry))?,
+            }
+        }
+    };
+
+    Ok(BlockData {
+        block_id,
+        block_val,
+    })
+}
+
+// extra fields are kept with the subchunk instead of with the block
+fn without_extra_fields(entry: &PaletteEntry) -> PaletteEntry {
+    PaletteEntry {
+        name: entry.name.clone(),
+        properties: entry.properties.clone(),
+        extra_fields: Vec::new(),
+    }
+}
+
+// A world whose level.dat is missing or cannot be read is still opened, its
+// chunks are then saved in the format matching their chunk version.
+fn detect_target_version(path: &Path) -> Option<TargetVersion> {
+    raw::detect_target_version(path).unwrap_or(None)
+}
+
+// Data values are translated to block states if the target format uses
+// them. Blocks without a known translation keep their data value.
+fn palette_entry(name: String, val: u16, target: TargetVersion) -> PaletteEntry {
+    let state = if target.block_states() {
+        BlockState::from_legacy(&name, val)
+    } else {
+        None
+    };
+
+    let properties = match state {
+        Some(state) => BlockProperties::States {
+            states: state.states,
+            version: target.block_version(),
+        },
+        None => BlockProperties::Val(val),
+    };
+
+    PaletteEntry {
+        name,
+        properties,
+        extra_fields: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw::CountingStorage;
+    use crate::state::{StateValue, States};
+
+    const ORIGIN: ChunkPos = ChunkPos {
+        x: 0,
+        z: 0,
+        dimension: Dimension::Overworld,
+    };
+
+    const AIR: BlockLayers = BlockLayers {
+        layer1: AIR_INFO,
+        layer2: AIR_INFO,
+    };
+
+    fn entry(name: &str, properties: BlockProperties) -> PaletteEntry {
+        PaletteEntry {
+            name: name.to_owned(),
+            properties,
+            extra_fields: Vec::new(),
+        }
+    }
+
+    // a subchunk with the given entry at the bottom and air above it
+    fn subchunk_with(bottom: PaletteEntry) -> Subchunk {
+        let air = entry("minecraft:air", BlockProperties::Val(0));
+        let blocks = (0..4096).map(|i| if i % 16 == 0 { 1 } else { 0 }).collect();
+
+        Subchunk {
+            version: 8,
+            block_storages: vec![BlockStorage {
+                blocks,
+                palette: vec![air, bottom],
+            }],
+            opaque_storages: None,
+        }
+    }
+
+    // a block with a state that no version of the game has, so it can only
+    // be written with block states
+    fn unknown_state_entry(version: Option<i32>) -> PaletteEntry {
+        let mut states = States::new();
+        states.insert("unknown_state".to_owned(), StateValue::Int(3));
+        entry(
+            "minecraft:stone",
+            BlockProperties::States { states, version },
+        )
+    }
+
+    fn world_pos(x: i32, y: u8, z: i32) -> WorldPos {
+        WorldPos {
+            x,
+            y,
+            z,
+            dimension: Dimension::Overworld,
+        }
+    }
+
+    fn layers(world: &World, name: &str) -> BlockLayers {
+        BlockLayers {
+            layer1: BlockData {
+                block_id: world.block_id(name),
+                block_val: 0,
+            },
+            layer2: AIR_INFO,
+        }
+    }
+
+    // a world whose storage counts the records written in each write
+    fn counting_world(target: TargetVersion) -> (World, Arc<Mutex<Vec<usize>>>) {
+        let storage = CountingStorage::default();
+        let writes = storage.writes.clone();
+        let raw_world = RawWorld::with_storage(Box::new(storage));
+        (World::with_raw_world(raw_world, Some(target)), writes)
+    }
+
+    // a subchunk of the chunk at the origin, as it is stored
+    fn saved_subchunk(world: &World, y: u8) -> Subchunk {
+        let pos = ORIGIN.subchunk_pos(y);
+        world.raw_world().load_subchunk(&pos).unwrap().unwrap()
+    }
+
+    // all records of the world, in the order of their keys
+    fn records(world: &World) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut records = Vec::new();
+        let mut iter = world.raw_world().storage().iter();
+        iter.seek_to_first();
+        while iter.valid() {
+            records.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+
+        records
+    }
+
+    #[test]
+    fn states_without_data_value_are_kept() {
+        let original = unknown_state_entry(Some(17_694_723));
+
+        let world = World::in_memory(TargetVersion::LATEST);
+        let pos = ORIGIN.subchunk_pos(0);
+        world
+            .raw_world()
+            .save_subchunk(&pos, &subchunk_with(original.clone()))
+            .unwrap();
+
+        // change another block, so the subchunk is written again
+        let dirt = layers(&world, "minecraft:dirt");
+        world.set_block(&world_pos(1, 1, 1), dirt).unwrap();
+        world.save().unwrap();
+
+        let saved = saved_subchunk(&world, 0);
+        assert!(saved.block_storages[0].palette.contains(&original));
+
+        // the block cannot be written in a format without block states
+        let old_world = World::in_memory(TargetVersion::DataValues);
+        old_world
+            .raw_world()
+            .save_subchunk(&pos, &subchunk_with(original))
+            .unwrap();
+        old_world.set_block(&world_pos(1, 1, 1), dirt).unwrap();
+        assert!(old_world.save().is_err());
+    }
+
+    #[test]
+    fn saving_gives_the_same_bytes() {
+        let save = || {
+            let world = World::in_memory(TargetVersion::LATEST);
+            world.add_chunk(ORIGIN).unwrap();
+
+            // the first block is not air, and the log has several states
+            let log = BlockLayers {
+                layer1: BlockData {
+                    block_id: world.block_id("minecraft:log"),
+                    block_val: 9,
+                },
+                layer2: AIR_INFO,
+            };
+            world
+                .set_block(&world_pos(0, 0, 0), layers(&world, "minecraft:stone"))
+                .unwrap();
+            world.set_block(&world_pos(2, 2, 2), log).unwrap();
+            world.save().unwrap();
+            let first = records(&world);
+
+            // writing the unchanged chunk again gives the same records
+            let chunk = world.chunk(ORIGIN).unwrap().unwrap();
+            world.put_chunk(ORIGIN, chunk).unwrap();
+            world.save().unwrap();
+            assert_eq!(records(&world), first);
+
+            let saved = saved_subchunk(&world, 0);
+            assert_eq!(saved.block_storages[0].palette[0].name, "minecraft:air");
+
+            first
+        };
+
+        // the palettes and the order of the fields do not depend on the
+        // order of any hash map
+        assert_eq!(save(), save());
+    }
+
+    #[test]
+    fn reading_writes_nothing() {
+        let (world, writes) = counting_world(TargetVersion::LATEST);
+
+        world.add_chunk(ORIGIN).unwrap();
+        let stone = layers(&world, "minecraft:stone");
+        world.set_block(&world_pos(0, 0, 0), stone).unwrap();
+        world.save().unwrap();
+        world.unload_chunk(ORIGIN).unwrap();
+        writes.lock().clear();
+
+        // reading loads subchunks, which does not make them dirty
+        assert_eq!(world.get_block(&world_pos(0, 0, 0)).unwrap(), Some(stone));
+        world.chunk(ORIGIN).unwrap().unwrap();
+        world
+            .with_chunk(ORIGIN, |chunk| chunk.get(1, 100, 1))
+            .unwrap();
+
+        // a chunk that never existed has nothing to delete
+        let absent = ChunkPos { x: 5, ..ORIGIN };
+        world.delete_chunk(absent).unwrap();
+        world.save().unwrap();
+
+        assert_eq!(writes.lock().iter().sum::<usize>(), 0);
+    }
+
+    #[test]
+    fn air_above_the_terrain_is_left_out() {
+        let world = World::in_memory(TargetVersion::LATEST);
+        world.add_chunk(ORIGIN).unwrap();
+        let stone = layers(&world, "minecraft:stone");
+        world.set_block(&world_pos(0, 0, 0), stone).unwrap();
+        world.set_block(&world_pos(0, 40, 0), stone).unwrap();
+        world.save().unwrap();
+
+        let stored = |y| {
+            let pos = ORIGIN.subchunk_pos(y);
+            world.raw_world().load_subchunk(&pos).unwrap()
+        };
+        // the air subchunk below the stone is kept, the ones above are not
+        assert!(stored(1).is_some());
+        assert!(stored(2).is_some());
+        assert!(stored(3).is_none());
+        // the second layer is empty, so it is not written
+        assert_eq!(stored(0).unwrap().block_storages.len(), 1);
+
+        // once the top stone is gone, the stale subchunks are deleted
+        world.set_block(&world_pos(0, 40, 0), AIR).unwrap();
+        world.save().unwrap();
+        assert!(stored(0).is_some());
+        assert!(stored(1).is_none());
+        assert!(stored(2).is_none());
+
+        let water = BlockLayers {
+            layer2: layers(&world, "minecraft:water").layer1,
+            ..stone
+        };
+        world.set_block(&world_pos(0, 0, 0), water).unwrap();
+        world.save().unwrap();
+        assert_eq!(stored(0).unwrap().block_storages.len(), 2);
+    }
+
+    #[test]
+    fn failed_save_changes_nothing() {
+        let (world, writes) = counting_world(TargetVersion::DataValues);
+
+        let unwritable = unknown_state_entry(None);
+        let other = ChunkPos { x: 1, ..ORIGIN };
+        world
+            .raw_world()
+            .save_subchunk(&other.subchunk_pos(0), &subchunk_with(unwritable))
+            .unwrap();
+        world.add_chunk(ORIGIN).unwrap();
+        let before = records(&world);
+        writes.lock().clear();
+
+        let dirt = layers(&world, "minecraft:dirt");
+        world.set_block(&world_pos(0, 0, 0), dirt).unwrap();
+        world.set_block(&world_pos(17, 1, 0), dirt).unwrap();
+        assert!(world.save().is_err());
+
+        assert!(writes.lock().is_empty());
+        assert_eq!(records(&world), before);
+        // the changes are still there to be saved
+        assert_eq!(world.get_block(&world_pos(0, 0, 0)).unwrap(), Some(dirt));
+    }
+
+    #[test]
+    fn block_version_matches_target() {
+        for &target in &[TargetVersion::EarlyBlockStates, TargetVersion::BlockStates] {
+            let world = World::in_memory(target);
+            world.add_chunk(ORIGIN).unwrap();
+            let stone = layers(&world, "minecraft:stone");
+            world.set_block(&world_pos(0, 0, 0), stone).unwrap();
+            world.save().unwrap();
+
+            let saved = saved_subchunk(&world, 0);
+            for entry in &saved.block_storages[0].palette {
+                match entry.properties {
+                    BlockProperties::States { version, .. } => {
+                        assert_eq!(version, target.block_version())
+                    }
+                    BlockProperties::Val(_) => panic!("expected block states"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unreadable_level_dat_is_ignored() {
+        let dir = std::env::temp_dir().join(format!("mcworld-level-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("level.dat"), b"not a level").unwrap();
+
+        let target = detect_target_version(&dir.join("db"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(target, None);
+    }
+
+    #[test]
+    fn set_block_needs_a_chunk() {
+        let world = World::in_memory(TargetVersion::LATEST);
+        let stone = layers(&world, "minecraft:stone");
+        assert!(world.set_block(&world_pos(0, 0, 0), stone).is_err());
+
+        world.add_chunk(ORIGIN).unwrap();
+        world.delete_chunk(ORIGIN).unwrap();
+        assert!(world.set_block(&world_pos(0, 0, 0), stone).is_err());
+        assert_eq!(world.get_block(&world_pos(0, 0, 0)).unwrap(), None);
+    }
+
+    #[test]
+    fn subchunk_of_unknown_storages_is_kept() {
+        let world = World::in_memory(TargetVersion::LATEST);
+        // the position of the origin, the subchunk tag and the height
+        let key = [0, 0, 0, 0, 0, 0, 0, 0, 47, 0];
+
+        // a single storage with 7 bits per block, which is not a known format
+        let data = vec![8, 1, 7 << 1, 1, 2, 3];
+        world.raw_world().put_bytes(&key, &data).unwrap();
+
+        // a change that is undone leaves the storage as it was
+        let wp = world_pos(0, 0, 0);
+        world
+            .set_block(&wp, layers(&world, "minecraft:stone"))
+            .unwrap();
+        world.set_block(&wp, AIR).unwrap();
+        world.save().unwrap();
+        assert_eq!(world.raw_world().get_bytes(&key).unwrap().unwrap(), data);
+
+        // blocks placed in the subchunk are written in front of it
+        world
+            .set_block(&wp, layers(&world, "minecraft:stone"))
+            .unwrap();
+        world.save().unwrap();
+        let saved = saved_subchunk(&world, 0);
+        assert_eq!(saved.block_storages.len(), 1);
+        assert_eq!(saved.opaque_storages.unwrap().data, &data[2..]);
+    }
+
+    #[test]
+    fn changed_chunks_are_kept_until_saved() {
+        let mut world = World::in_memory(TargetVersion::LATEST);
+        // a single chunk for each cache
+        world.set_cache_capacity(Some(NUM_SHARDS));
+        let cached = |world: &World| -> usize {
+            world
+                .chunk_cache
+                .iter()
+                .map(|shard| shard.lock().len())
+                .sum()
+        };
+
+        let stone = layers(&world, "minecraft:stone");
+        for x in 0..64 {
+            let pos = ChunkPos { x, ..ORIGIN };
+            world.add_chunk(pos).unwrap();
+            world.set_block(&world_pos(x * 16, 0, 0), stone).unwrap();
+        }
+        assert_eq!(cached(&world), 64);
+
+        world.save().unwrap();
+        world.set_cache_capacity(Some(NUM_SHARDS));
+        assert!(cached(&world) <= NUM_SHARDS);
+
+        // the least recently used chunk of a cache is dropped first
+        let first = ChunkPos { x: 0, ..ORIGIN };
+        let second = (1..)
+            .map(|x| ChunkPos { x, ..ORIGIN })
+            .find(|pos| std::ptr::eq(world.shard(pos), world.shard(&first)))
+            .unwrap();
+        world.get_block(&world_pos(0, 0, 0)).unwrap();
+        world.get_block(&world_pos(second.x * 16, 0, 0)).unwrap();
+        let cache = world.shard(&first).lock();
+        assert!(cache.get(&first).is_none());
+        assert!(cache.get(&second).is_some());
+        drop(cache);
+
+        // the dropped chunks were saved
+        for x in 0..64 {
+            let block = world.get_block(&world_pos(x * 16, 0, 0)).unwrap();
+            assert_eq!(block, Some(stone));
+        }
+    }
+
+    #[test]
+    fn palette_translation_round_trip() {
+        let names = ["minecraft:air", "minecraft:stone", "minecraft:dirt"];
+        // the last two entries repeat a block and are not used at all
+        let palette: Vec<_> = names
+            .iter()
+            .chain(&["minecraft:stone", "minecraft:grass"])
+            .map(|name| entry(name, BlockProperties::Val(0)))
+            .collect();
+        let blocks: Vec<u16> = (0..4096).map(|i| (i * 7 % 4) as u16).collect();
+        let sc = Subchunk {
+            version: 8,
+            block_storages: vec![BlockStorage { blocks, palette }],
+            opaque_storages: None,
+        };
+
+        let world = World::in_memory(TargetVersion::LATEST);
+        let pos = ORIGIN.subchunk_pos(0);
+        world.raw_world().save_subchunk(&pos, &sc).unwrap();
+
+        let name_at = |sc: &Subchunk, offset: usize| {
+            let storage = &sc.block_storages[0];
+            storage.palette[usize::from(storage.blocks[offset])]
+                .name
+                .clone()
+        };
+        let chunk = world.chunk(ORIGIN).unwrap().unwrap();
+        for (local, data) in chunk.subchunk(0).iter() {
+            let expected = name_at(&sc, local.subchunk_offset());
+            assert_eq!(world.block_name(data.layer1.block_id), expected);
+        }
+
+        // the subchunk is written again once a block in it changes
+        let dirt = layers(&world, "minecraft:dirt");
+        let changed = world_pos(15, 15, 15);
+        world.set_block(&changed, dirt).unwrap();
+        world.save().unwrap();
+
+        let saved = saved_subchunk(&world, 0);
+        for offset in 0..4096 {
+            let expected = if offset == changed.local_pos().subchunk_offset() {
+                "minecraft:dirt".to_owned()
+            } else {
+                name_at(&sc, offset)
+            };
+            assert_eq!(name_at(&saved, offset), expected);
+        }
+        assert_eq!(saved.block_storages[0].palette.len(), names.len());
+    }
+
+    // the heights of the subchunks of a cached chunk which were decoded
+    fn loaded_subchunks(world: &World, pos: ChunkPos) -> Vec<usize> {
+        let cached = world.shard(&pos).lock().get(&pos).cloned().unwrap();
+        let loaded = match &*cached.lock() {
+            CachedChunk::Present(chunk) => (0..chunk.subchunks.len())
+                .filter(|&y| chunk.subchunks[y].is_some())
+                .collect(),
+            _ => panic!("expected the chunk to be cached"),
+        };
+        loaded
+    }
+
+    #[test]
+    fn subchunks_are_decoded_when_used() {
+        let world = World::in_memory(TargetVersion::LATEST);
+        world.add_chunk(ORIGIN).unwrap();
+        let stone = layers(&world, "minecraft:stone");
+        world.set_block(&world_pos(0, 100, 0), stone).unwrap();
+        world.save().unwrap();
+        world.unload_chunk(ORIGIN).unwrap();
+
+        // the bottom subchunk covers the blocks at heights 0 to 15
+        for y in 0..16 {
+            assert_eq!(world.get_block(&world_pos(3, y, 5)).unwrap(), Some(AIR));
+        }
+        assert_eq!(loaded_subchunks(&world, ORIGIN), vec![0]);
+
+        assert_eq!(world.get_block(&world_pos(0, 100, 0)).unwrap(), Some(stone));
+        assert_eq!(loaded_subchunks(&world, ORIGIN), vec![0, 6]);
+    }
+
+    #[test]
+    fn par_for_each_chunk_loads_used_subchunks() {
+        let world = World::in_memory(TargetVersion::LATEST);
+        let stone = layers(&world, "minecraft:stone");
+        for x in 0..4 {
+            world.add_chunk(ChunkPos { x, ..ORIGIN }).unwrap();
+            world.set_block(&world_pos(x * 16, 40, 0), stone).unwrap();
+        }
+        world.save().unwrap();
+        for x in 0..4 {
+            world.unload_chunk(ChunkPos { x, ..ORIGIN }).unwrap();
+        }
+
+        let found = std::sync::atomic::AtomicUsize::new(0);
+        world
+            .par_for_each_chunk(|world, pos| {
+                let wp = world_pos(pos.x * 16, 40, 0);
+                if world.get_block(&wp)? == Some(stone) {
+                    found.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(found.into_inner(), 4);
+
+        // only the subchunk holding the block was decoded
+        assert_eq!(loaded_subchunks(&world, ORIGIN), vec![2]);
+    }
+
+    #[test]
+    fn chunks_are_only_put_into_their_world() {
+        let world = World::in_memory(TargetVersion::LATEST);
+        let other = World::in_memory(TargetVersion::LATEST);
+        other.add_chunk(ORIGIN).unwrap();
+        let stone = layers(&other, "minecraft:stone");
+        other.set_block(&world_pos(0, 0, 0), stone).unwrap();
+
+        let chunk = other.chunk(ORIGIN).unwrap().unwrap();
+        assert!(world.put_chunk(ORIGIN, chunk.clone()).is_err());
+        assert_eq!(world.get_block(&world_pos(0, 0, 0)).unwrap(), None);
+
+        let moved = ChunkPos { x: 1, ..ORIGIN };
+        other.put_chunk(moved, chunk).unwrap();
+        assert_eq!(other.get_block(&world_pos(16, 0, 0)).unwrap(), Some(stone));
+    }
+
+    #[test]
+    fn with_chunk_cannot_use_the_world() {
+        let world = World::in_memory(TargetVersion::LATEST);
+        world.add_chunk(ORIGIN).unwrap();
+        let other = ChunkPos { x: 1, ..ORIGIN };
+        world.add_chunk(other).unwrap();
+
+        let result = world
+            .with_chunk(ORIGIN, |_| world.get_block(&world_pos(16, 0, 0)))
+            .unwrap()
+            .unwrap();
+        assert!(result.is_err());
+        let result = world
+            .with_chunk(ORIGIN, |_| world.with_chunk(other, |_| ()))
+            .unwrap()
+            .unwrap();
+        assert!(result.is_err());
+
+        // another world can be used
+        let copy = World::in_memory(TargetVersion::LATEST);
+        let result = world
+            .with_chunk(ORIGIN, |_| copy.add_chunk(ORIGIN))
+            .unwrap()
+            .unwrap();
+        assert!(result.is_ok());
+
+        // once `f` returns, the world can be used again
+        assert_eq!(world.get_block(&world_pos(16, 0, 0)).unwrap(), Some(AIR));
+    }
+
+    #[test]
+    fn transaction_set_block_needs_a_chunk() {
+        let world = World::in_memory(TargetVersion::LATEST);
+        world.add_chunk(ORIGIN).unwrap();
+        let stone = layers(&world, "minecraft:stone");
+
+        let result = world.transaction(|tx| {
+            tx.set_block(&world_pos(0, 0, 0), stone)?;
+            tx.delete_chunk(ORIGIN);
+            tx.set_block(&world_pos(0, 0, 0), stone)
+        });
+        assert!(result.is_err());
+        assert_ne!(world.get_block(&world_pos(0, 0, 0)).unwrap(), Some(stone));
+
+        let result = world.transaction(|tx| tx.set_block(&world_pos(16, 0, 0), stone));
+        assert!(result.is_err());
+        assert_eq!(world.get_block(&world_pos(16, 0, 0)).unwrap(), None);
+    }
+
+    #[test]
+    fn failed_commit_changes_nothing() {
+        let world = World::in_memory(TargetVersion::LATEST);
+        let deleted = ORIGIN;
+        let kept = ChunkPos { x: -1, ..ORIGIN };
+        world.add_chunk(deleted).unwrap();
+        world.add_chunk(kept).unwrap();
+        let stone = layers(&world, "minecraft:stone");
+
+        let result = world.transaction(|tx| {
+            tx.add_chunk(ChunkPos { x: 1, ..ORIGIN });
+            tx.set_block(&world_pos(-16, 0, 0), stone)?;
+            tx.set_block(&world_pos(0, 0, 0), stone)?;
+            // the chunk is gone by the time the changes are committed
+            world.delete_chunk(deleted)
+        });
+
+        assert!(result.is_err());
+        assert_eq!(world.get_block(&world_pos(-16, 0, 0)).unwrap(), Some(AIR));
+        assert_eq!(world.get_block(&world_pos(16, 0, 0)).unwrap(), None);
+    }
+
+    #[test]
+    fn saves_do_not_wait_for_other_threads_forever() {
+        let world = Arc::new(World::in_memory(TargetVersion::LATEST));
+        for x in 0..4 {
+            world.add_chunk(ChunkPos { x, ..ORIGIN }).unwrap();
+        }
+        let stone = layers(&world, "minecraft:stone");
+
+        let editor = {
+            let world = world.clone();
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    let pos = ChunkPos { x: i % 4, ..ORIGIN };
+                    world
+                        .with_chunk(pos, |chunk| chunk.set(0, (i % 256) as u8, 0, stone))
+                        .unwrap();
+                }
+            })
+        };
+        let committer = {
+            let world = world.clone();
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    // the chunks are changed in the opposite order of their
+                    // positions
+                    world
+                        .transaction(|tx| {
+                            tx.set_block(&world_pos(48, (i % 256) as u8, 1), stone)?;
+                            tx.set_block(&world_pos(0, (i % 256) as u8, 1), stone)
+                        })
+                        .unwrap();
+                }
+            })
+        };
+        for _ in 0..200 {
+            world.save().unwrap();
+        }
+
+        editor.join().unwrap();
+        committer.join().unwrap();
+        world.save().unwrap();
+        assert_eq!(
+            world.get_block(&world_pos(48, 199, 1)).unwrap(),
+            Some(stone)
+        );
+    }
+
+    #[test]
+    fn transaction_is_undone_as_one_operation() {
+        let mut world = World::in_memory(TargetVersion::LATEST);
+        world.enable_history(1000);
+        world.add_chunk(ORIGIN).unwrap();
+        let stone = layers(&world, "minecraft:stone");
+        let added = ChunkPos { x: 1, ..ORIGIN };
+
+        world
+            .transaction(|tx| {
+                tx.add_chunk(added);
+                tx.set_block(&world_pos(0, 0, 0), stone)?;
+                tx.set_block(&world_pos(16, 20, 0), stone)?;
+                // changes are seen before they are committed
+                assert_eq!(tx.get_block(&world_pos(16, 20, 0))?, Some(stone));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(world.get_block(&world_pos(0, 0, 0)).unwrap(), Some(stone));
+        assert_eq!(world.get_block(&world_pos(16, 20, 0)).unwrap(), Some(stone));
+
+        // adding the chunk is not recorded, its blocks are
+        assert_eq!(world.undo().unwrap(), Some("transaction".to_owned()));
+        assert_eq!(world.get_block(&world_pos(0, 0, 0)).unwrap(), Some(AIR));
+        assert_eq!(world.get_block(&world_pos(16, 20, 0)).unwrap(), Some(AIR));
+        assert_eq!(world.undo().unwrap(), None);
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut world = World::in_memory(TargetVersion::LATEST);
+        world.enable_history(1000);
+        world.add_chunk(ORIGIN).unwrap();
+        let pos = world_pos(3, 3, 3);
+        let stone = layers(&world, "minecraft:stone");
+        let dirt = layers(&world, "minecraft:dirt");
+
+        world.operation("place", || {
+            world.set_block(&pos, stone).unwrap();
+            world.set_block(&pos, dirt).unwrap();
+        });
+        world.set_block(&pos, stone).unwrap();
+
+        assert_eq!(world.undo().unwrap(), Some("set block".to_owned()));
+        assert_eq!(world.get_block(&pos).unwrap(), Some(dirt));
+        assert_eq!(world.undo().unwrap(), Some("place".to_owned()));
+        assert_eq!(world.get_block(&pos).unwrap(), Some(AIR));
+        assert_eq!(world.undo().unwrap(), None);
+
+        assert_eq!(world.redo().unwrap(), Some("place".to_owned()));
+        assert_eq!(world.get_block(&pos).unwrap(), Some(dirt));
+
+        // a new change cannot be followed by the undone ones
+        world.set_block(&pos, AIR).unwrap();
+        assert_eq!(world.redo().unwrap(), None);
+    }
+
+    #[test]
+    fn undone_operations_are_forgotten_first() {
+        let mut world = World::in_memory(TargetVersion::LATEST);
+        world.enable_history(4);
+        world.add_chunk(ORIGIN).unwrap();
+        let stone = layers(&world, "minecraft:stone");
+
+        for (name, y) in &[("first", 0), ("second", 2)] {
+            world.operation(name, || {
+                world.set_block(&world_pos(0, *y, 0), stone).unwrap();
+                world.set_block(&world_pos(0, *y + 1, 0), stone).unwrap();
+            });
+        }
+        assert_eq!(world.undo().unwrap(), Some("second".to_owned()));
+
+        // the history is full, the undone operation makes room for this one
+        world.set_block(&world_pos(0, 10, 0), stone).unwrap();
+        assert_eq!(world.redo().unwrap(), None);
+        assert_eq!(world.undo().unwrap(), Some("set block".to_owned()));
+        assert_eq!(world.undo().unwrap(), Some("first".to_owned()));
+        assert_eq!(world.get_block(&world_pos(0, 0, 0)).unwrap(), Some(AIR));
+    }
+
+    #[test]
+    fn operation_larger_than_the_history_is_not_kept() {
+        let mut world = World::in_memory(TargetVersion::LATEST);
+        world.enable_history(3);
+        world.add_chunk(ORIGIN).unwrap();
+        let stone = layers(&world, "minecraft:stone");
+        let dirt = layers(&world, "minecraft:dirt");
+
+        world.set_block(&world_pos(0, 0, 0), dirt).unwrap();
+        world.operation("fill", || {
+            for y in 0..4 {
+                world.set_block(&world_pos(1, y, 0), stone).unwrap();
+            }
+        });
+
+        // the operation before it can still be undone
+        assert_eq!(world.get_block(&world_pos(1, 3, 0)).unwrap(), Some(stone));
+        assert_eq!(world.undo().unwrap(), Some("set block".to_owned()));
+        assert_eq!(world.get_block(&world_pos(0, 0, 0)).unwrap(), Some(AIR));
+        assert_eq!(world.undo().unwrap(), None);
+    }
+
+    #[test]
+    fn failed_undo_is_kept() {
+        let mut world = World::in_memory(TargetVersion::LATEST);
+        world.enable_history(1000);
+        let other = ChunkPos { x: 1, ..ORIGIN };
+        world.add_chunk(ORIGIN).unwrap();
+        world.add_chunk(other).unwrap();
+        let stone = layers(&world, "minecraft:stone");
+
+        world.operation("place", || {
+            world.set_block(&world_pos(0, 0, 0), stone).unwrap();
+            world.set_block(&world_pos(16, 0, 0), stone).unwrap();
+        });
+        world.delete_chunk(other).unwrap();
+
+        // no block is reverted while one of them cannot be
+        assert!(world.undo().is_err());
+        assert_eq!(world.get_block(&world_pos(0, 0, 0)).unwrap(), Some(stone));
+
+        world.add_chunk(other).unwrap();
+        assert_eq!(world.undo().unwrap(), Some("place".to_owned()));
+        assert_eq!(world.get_block(&world_pos(0, 0, 0)).unwrap(), Some(AIR));
+    }
+
+    // remembers what it was told, in order
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl WorldObserver for RecordingObserver {
+        fn block_changed(&self, pos: &WorldPos, _old: BlockLayers, _new: BlockLayers) {
+            self.events
+                .lock()
+                .push(format!("block {} {} {}", pos.x, pos.y, pos.z));
+        }
+
+        fn chunk_added(&self, pos: ChunkPos) {
+            self.events.lock().push(format!("added {}", pos.x));
+        }
+
+        fn chunk_deleted(&self, pos: ChunkPos) {
+            self.events.lock().push(format!("deleted {}", pos.x));
+        }
+
+        fn saved(&self) {
+            self.events.lock().push("saved".to_owned());
+        }
+    }
+
+    #[test]
+    fn observers_see_changes() {
+        let mut world = World::in_memory(TargetVersion::LATEST);
+        let observer = Arc::new(RecordingObserver::default());
+        world.add_observer(observer.clone());
+
+        world.add_chunk(ORIGIN).unwrap();
+        let stone = layers(&world, "minecraft:stone");
+        world.set_block(&world_pos(1, 2, 3), stone).unwrap();
+        // setting a block to what it is already changes nothing
+        world.set_block(&world_pos(1, 2, 3), stone).unwrap();
+        world
+            .with_chunk(ORIGIN, |chunk| chunk.set(4, 5, 6, stone))
+            .unwrap();
+        world.save().unwrap();
+
+        world.delete_chunk(ORIGIN).unwrap();
+        // there is nothing left to delete
+        world.delete_chunk(ORIGIN).unwrap();
+        world.delete_chunk(ChunkPos { x: 1, ..ORIGIN }).unwrap();
+
+        let events = observer.events.lock().clone();
+        let expected = [
+            "added 0",
+            "block 1 2 3",
+            "block 4 5 6",
+            "saved",
+            "deleted 0",
+        ];
+        assert_eq!(events, expected);
+    }
 }